@@ -10,13 +10,13 @@ use minifb::{Key, KeyRepeat, Menu, MENU_KEY_CTRL, Scale, ScaleMode, Window, Wind
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpec, AudioSpecDesired};
 use sdl2::controller::{Button, GameController};
 use sdl2::event::Event;
-use sdl2::EventPump;
+use sdl2::{AudioSubsystem, EventPump};
 use sdl2::messagebox::{ButtonData, MessageBoxButtonFlag, MessageBoxFlag, show_message_box};
-use nes_core::apu::{AudioChannels};
+use nes_core::apu::{AudioChannels, AudioFilterProfile, AudioRecorder, StereoMix};
 use nes_core::cartridge;
 use nes_core::input::JoypadButtons;
 use nes_core::nes::{NES};
-use nes_core::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH, SCREEN_PIXELS};
+use nes_core::ppu::{BuiltinPalette, Palette, SCREEN_HEIGHT, SCREEN_WIDTH, SCREEN_PIXELS};
 
 const TRACE_FILE: bool = false;
 
@@ -70,10 +70,34 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
     file_menu.add_item("Open", ACTION_OPEN).shortcut(Key::O, MENU_KEY_CTRL).build();
     file_menu.add_item("Reset", ACTION_RESET).build();
     file_menu.add_item("Stop", ACTION_STOP).build();
+    file_menu.add_separator();
+    file_menu.add_item("Start Recording...", ACTION_START_RECORDING).build();
+    file_menu.add_item("Start Recording with Stems...", ACTION_START_RECORDING_STEMS).build();
+    file_menu.add_item("Stop Recording", ACTION_STOP_RECORDING).build();
+    file_menu.add_separator();
+    file_menu.add_item("Start APU Register Log", ACTION_START_REGISTER_LOG).build();
+    file_menu.add_item("Save APU Register Log...", ACTION_SAVE_REGISTER_LOG).build();
     window.add_menu(&file_menu);
 
-    let audio_device: AudioDevice<NesAudioCallback> = create_audio_device(&sdl_context);
-    info!("Got audio device: {:?}", audio_device.spec());
+    let mut palette_menu = Menu::new("Palette")?;
+    for (i, palette) in BuiltinPalette::ALL.iter().enumerate() {
+        palette_menu.add_item(palette.name(), ACTION_BUILTIN_PALETTE + i).build();
+    }
+    palette_menu.add_separator();
+    palette_menu.add_item("Load .pal File...", ACTION_LOAD_PALETTE).build();
+    window.add_menu(&palette_menu);
+
+    let mut audio_menu = Menu::new("Audio")?;
+    for (i, profile) in AudioFilterProfile::ALL.iter().enumerate() {
+        audio_menu.add_item(profile.name(), ACTION_AUDIO_FILTER + i).build();
+    }
+    audio_menu.add_separator();
+    audio_menu.add_item("Toggle Stereo (Split Pulses)", ACTION_TOGGLE_STEREO).build();
+    audio_menu.add_item("Toggle Silencing Ultrasonic Triangle", ACTION_TOGGLE_ULTRASONIC_TRIANGLE).build();
+    window.add_menu(&audio_menu);
+
+    let audio_subsystem = sdl_context.audio()?;
+    let audio_device: AudioDevice<NesAudioCallback> = create_audio_device(&audio_subsystem, 1);
 
     let mut controller_mappings = &include_bytes!("../gamecontrollerdb.txt")[..];
     controller_subsystem.load_mappings_from_read(&mut controller_mappings).unwrap();
@@ -82,7 +106,7 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
     let mut event_pump: EventPump = sdl_context.event_pump()?;
 
     let mut frame_stats = FrameStats::new();
-    let mut app = App::new(audio_device);
+    let mut app = App::new(audio_subsystem, audio_device);
     while window.is_open() {
         let start_time = Instant::now();
 
@@ -104,6 +128,20 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
             ACTION_OPEN => app.open_file_dialog(),
             ACTION_STOP => app.close_rom(),
             ACTION_RESET => app.reset(),
+            ACTION_LOAD_PALETTE => app.open_palette_dialog(),
+            ACTION_TOGGLE_STEREO => app.toggle_stereo(),
+            ACTION_TOGGLE_ULTRASONIC_TRIANGLE => app.toggle_silence_ultrasonic_triangle(),
+            ACTION_START_RECORDING => app.start_recording(false),
+            ACTION_START_RECORDING_STEMS => app.start_recording(true),
+            ACTION_STOP_RECORDING => app.stop_recording(),
+            ACTION_START_REGISTER_LOG => app.start_register_log(),
+            ACTION_SAVE_REGISTER_LOG => app.save_register_log(),
+            action if (ACTION_BUILTIN_PALETTE..ACTION_BUILTIN_PALETTE + BuiltinPalette::ALL.len()).contains(&action) => {
+                app.palette = Palette::builtin(BuiltinPalette::ALL[action - ACTION_BUILTIN_PALETTE]);
+            }
+            action if (ACTION_AUDIO_FILTER..ACTION_AUDIO_FILTER + AudioFilterProfile::ALL.len()).contains(&action) => {
+                app.set_audio_filter(AudioFilterProfile::ALL[action - ACTION_AUDIO_FILTER]);
+            }
             _ => {}
         }
         for event in event_pump.poll_iter() {
@@ -132,31 +170,34 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
         if !app.paused && has_focus {
             if let Some(nes) = app.nes.as_mut() {
                 app.audio_device.resume();
-                loop {
-                    let buffered_audio_frames = app.audio_device.lock().buffered_frames as usize;
-
-                    // Prevent the display buffering getting ahead of the audio buffering.
-                    while buffered_audio_frames < app.display_buffer.buffered_frames.len() {
-                        app.display_buffer.buffered_frames.pop_front();
-                    }
-
-                    // Buffer two frames worth of audio
-                    if buffered_audio_frames >= 2 {
-                        if let Some(frame) = app.display_buffer.buffered_frames.pop_front() {
-                            window.update_with_buffer(&frame.pixels[..], SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize)?;
-                            break;
+                nes.input.update_p1_key_state(get_pressed_buttons(&window, game_controller.as_ref()));
+                nes.input.update_p2_key_state(JoypadButtons::empty()); // Not implemented
+
+                nes.simulate_frame();
+
+                // The audio device runs at its own rate, not quite in time with the display, so
+                // keep its buffer from running dry or filling up by nudging the sample rate.
+                let buffer_fill = app.audio_device.lock().buffer_fill();
+                nes.apu.adjust_rate_for_buffer_fill(buffer_fill);
+                let mut failed_recorder = None;
+                nes.apu.output_samples_with_channels(|samples, channels| {
+                    app.audio_device.lock().write_samples(samples);
+                    if let Some(recorder) = app.recorder.as_mut() {
+                        if let Err(e) = recorder.record_samples(channels) {
+                            warn!("Failed to record audio: {e}");
+                            failed_recorder = app.recorder.take();
                         }
                     }
-
-                    nes.input.update_p1_key_state(get_pressed_buttons(&window, game_controller.as_ref()));
-                    nes.input.update_p2_key_state(JoypadButtons::empty()); // Not implemented
-
-                    nes.simulate_frame();
-
-                    nes.apu.output_samples(|samples| app.audio_device.lock().write_samples(samples));
-
-                    app.display_buffer.buffer_frame(|frame| nes.ppu.output_display_buffer_u32_argb(frame));
+                });
+                if let Some(recorder) = failed_recorder {
+                    // Keep what was recorded so far, if possible
+                    if let Err(e) = recorder.finish(&mut nes.apu) {
+                        warn!("Failed to finish the recording: {e}");
+                    }
                 }
+
+                nes.ppu.output_display_buffer_u32_argb(&app.palette, &mut app.display_frame);
+                window.update_with_buffer(&app.display_frame[..], SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize)?;
             } else {
                 app.audio_device.pause();
                 app.clear_buffering();
@@ -178,25 +219,37 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
         frame_stats.add_reading(frame_time);
     }
 
+    app.stop_recording();
+    app.save_game();
     Ok(())
 }
 
 struct App {
+    audio_subsystem: AudioSubsystem,
     audio_device: AudioDevice<NesAudioCallback>,
     nes: Option<Box<NES>>,
     rom_filename: Option<PathBuf>,
     paused: bool,
-    display_buffer: DisplayBuffering,
+    display_frame: Box<[u32; SCREEN_PIXELS]>,
+    palette: Palette,
+    audio_filter: AudioFilterProfile,
+    silence_ultrasonic_triangle: bool,
+    recorder: Option<AudioRecorder>,
 }
 
 impl App {
-    fn new(audio_device: AudioDevice<NesAudioCallback>) -> App {
+    fn new(audio_subsystem: AudioSubsystem, audio_device: AudioDevice<NesAudioCallback>) -> App {
         App {
+            audio_subsystem,
             audio_device,
             nes: None,
             rom_filename: None,
             paused: false,
-            display_buffer: DisplayBuffering::new(),
+            display_frame: vec![0u32; SCREEN_PIXELS].try_into().unwrap(),
+            palette: Palette::default(),
+            audio_filter: AudioFilterProfile::NesFrontLoader,
+            silence_ultrasonic_triangle: false,
+            recorder: None,
         }
     }
 
@@ -209,10 +262,111 @@ impl App {
         self.load_rom(filename);
     }
 
+    fn open_palette_dialog(&mut self) {
+        let Some(filename) = rfd::FileDialog::new()
+            .set_title("Load Palette")
+            .add_filter(".PAL", &["pal"])
+            .pick_file() else { return; };
+
+        match Palette::load(&filename) {
+            Ok(palette) => self.palette = palette,
+            Err(e) => display_error_dialog("Failed to load the palette", &e.to_string()),
+        }
+    }
+
+    fn set_audio_filter(&mut self, profile: AudioFilterProfile) {
+        self.audio_filter = profile;
+        if let Some(nes) = self.nes.as_mut() {
+            nes.apu.set_filter_profile(profile);
+        }
+    }
+
+    fn toggle_silence_ultrasonic_triangle(&mut self) {
+        self.silence_ultrasonic_triangle = !self.silence_ultrasonic_triangle;
+        info!("Silence ultrasonic triangle: {}", self.silence_ultrasonic_triangle);
+        if let Some(nes) = self.nes.as_mut() {
+            nes.apu.set_silence_ultrasonic_triangle(self.silence_ultrasonic_triangle);
+        }
+    }
+
+    fn start_recording(&mut self, record_stems: bool) {
+        self.stop_recording();
+        let Some(nes) = self.nes.as_mut() else { return; };
+        let Some(filename) = rfd::FileDialog::new()
+            .set_title("Record Audio")
+            .add_filter(".WAV", &["wav"])
+            .set_file_name("recording.wav")
+            .save_file() else { return; };
+
+        match AudioRecorder::start(&filename, &mut nes.apu, record_stems) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => display_error_dialog("Failed to start recording", &e.to_string()),
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        // Recordings are always stopped before the NES they're recording goes away
+        let (Some(recorder), Some(nes)) = (self.recorder.take(), self.nes.as_mut()) else { return; };
+        if let Err(e) = recorder.finish(&mut nes.apu) {
+            display_error_dialog("Failed to save the recording", &e.to_string());
+        }
+    }
+
+    fn start_register_log(&mut self) {
+        if let Some(nes) = self.nes.as_mut() {
+            nes.apu.start_register_log();
+        }
+    }
+
+    /// Stops the register log and saves it, as VGM or CSV depending on the file extension.
+    fn save_register_log(&mut self) {
+        let Some(log) = self.nes.as_mut().and_then(|nes| nes.apu.stop_register_log()) else { return; };
+        let Some(filename) = rfd::FileDialog::new()
+            .set_title("Save APU Register Log")
+            .add_filter(".VGM", &["vgm"])
+            .add_filter(".CSV", &["csv"])
+            .set_file_name("music.vgm")
+            .save_file() else { return; };
+
+        let is_csv = filename.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("csv"));
+        let result = File::create(&filename).and_then(|file| {
+            let writer = BufWriter::new(file);
+            if is_csv { log.write_csv(writer) } else { log.write_vgm(writer) }
+        });
+        if let Err(e) = result {
+            display_error_dialog("Failed to save the APU register log", &e.to_string());
+        }
+    }
+
+    fn toggle_stereo(&mut self) {
+        // The recording can't change its number of channels
+        self.stop_recording();
+        let channels = if self.audio_device.spec().channels == 2 { 1 } else { 2 };
+        self.audio_device = create_audio_device(&self.audio_subsystem, channels);
+        if let Some(mut nes) = self.nes.take() {
+            self.apply_audio_settings(&mut nes);
+            self.nes = Some(nes);
+        }
+        self.clear_buffering();
+    }
+
+    /// Makes the NES output audio to match the audio device.
+    fn apply_audio_settings(&self, nes: &mut NES) {
+        let spec = self.audio_device.spec();
+        nes.apu.set_sample_rate(spec.freq as u32);
+        nes.apu.set_filter_profile(self.audio_filter);
+        nes.apu.set_silence_ultrasonic_triangle(self.silence_ultrasonic_triangle);
+        nes.apu.set_stereo_mix(if spec.channels == 2 { Some(StereoMix::split_pulses()) } else { None });
+    }
+
     fn load_rom(&mut self, rom_filename: PathBuf) {
+        self.stop_recording();
+        // Save first, as reopening the same game loads its save file
+        self.save_game();
         match load_nes_system(&rom_filename) {
-            Ok(nes) => {
+            Ok(mut nes) => {
                 self.clear_buffering();
+                self.apply_audio_settings(&mut nes);
                 self.nes = Some(nes);
                 self.rom_filename = Some(rom_filename);
             }
@@ -223,15 +377,25 @@ impl App {
     }
 
     fn close_rom(&mut self) {
+        self.stop_recording();
+        self.save_game();
         self.nes = None;
         self.rom_filename = None;
         self.audio_device.pause();
         self.clear_buffering();
     }
 
+    /// Saves the game's battery-backed or flash memory, if it has any.
+    fn save_game(&self) {
+        let Some(nes) = self.nes.as_ref() else { return; };
+        if let Err(e) = nes.mapper.save_persistent_data() {
+            display_error_dialog("Failed to save the game", &e.to_string());
+        }
+    }
+
     fn reset(&mut self) {
         if let Some(nes) = self.nes.as_mut() {
-            nes.do_reset_interrupt();
+            nes.reset();
         }
     }
 
@@ -241,45 +405,24 @@ impl App {
 
     fn clear_buffering(&mut self) {
         self.audio_device.lock().clear_samples();
-        self.display_buffer.clear();
-    }
-}
-
-struct DisplayFrame {
-    pixels: Box<[u32; SCREEN_PIXELS]>,
-}
-
-impl DisplayFrame {
-    fn new() -> DisplayFrame {
-        DisplayFrame { pixels: vec![0u32; SCREEN_PIXELS].try_into().unwrap() }
-    }
-}
-
-struct DisplayBuffering {
-    buffered_frames: VecDeque<DisplayFrame>,
-}
-
-impl DisplayBuffering {
-    fn new() -> DisplayBuffering {
-        DisplayBuffering {
-            buffered_frames: VecDeque::new(),
-        }
-    }
-
-    fn buffer_frame(&mut self, output_pixels: impl FnOnce(&mut [u32; SCREEN_PIXELS])) {
-        let mut buffer: DisplayFrame = DisplayFrame::new();
-        output_pixels(&mut buffer.pixels);
-        self.buffered_frames.push_back(buffer);
-    }
-
-    fn clear(&mut self) {
-        self.buffered_frames.clear();
     }
 }
 
 const ACTION_OPEN: usize = 1;
 const ACTION_STOP: usize = 2;
 const ACTION_RESET: usize = 3;
+const ACTION_LOAD_PALETTE: usize = 4;
+const ACTION_TOGGLE_STEREO: usize = 5;
+const ACTION_START_RECORDING: usize = 6;
+const ACTION_START_RECORDING_STEMS: usize = 7;
+const ACTION_STOP_RECORDING: usize = 8;
+const ACTION_START_REGISTER_LOG: usize = 9;
+const ACTION_SAVE_REGISTER_LOG: usize = 10;
+const ACTION_TOGGLE_ULTRASONIC_TRIANGLE: usize = 11;
+/// One action per built-in palette, from this number up
+const ACTION_BUILTIN_PALETTE: usize = 100;
+/// One action per audio filter profile, from this number up
+const ACTION_AUDIO_FILTER: usize = 200;
 
 fn load_nes_system(
     filename: &Path,
@@ -366,20 +509,31 @@ impl FrameStats {
     }
 }
 
+/// How many frames of audio to buffer. The sample rate is adjusted to keep the buffer half full,
+/// so it's two frames of latency.
+const AUDIO_BUFFER_FRAMES: usize = 4;
+
 pub struct NesAudioCallback {
     buffer: VecDeque<f32>,
-    buffered_frames: u32,
+    /// The number of samples in a full buffer
+    capacity: usize,
 }
 
 impl NesAudioCallback {
     pub fn write_samples(&mut self, samples: &[f32]) {
-        self.buffered_frames += 1;
         self.buffer.extend(samples);
     }
 
+    /// How full the buffer is, from 0.0 (empty) to 1.0 (full), for dynamic rate control.
+    pub fn buffer_fill(&self) -> f32 {
+        self.buffer.len() as f32 / self.capacity as f32
+    }
+
+    /// Empties the buffer, then fills it halfway with silence, so playback doesn't start with
+    /// the buffer running dry.
     pub fn clear_samples(&mut self) {
-        self.buffered_frames = 0;
         self.buffer.clear();
+        self.buffer.resize(self.capacity / 2, 0.0);
     }
 }
 
@@ -393,23 +547,28 @@ impl AudioCallback for NesAudioCallback {
         for x in out.iter_mut() {
             *x = self.buffer.pop_front().unwrap_or(0.0);
         }
-        self.buffered_frames = self.buffered_frames.saturating_sub(1);
     }
 }
 
-pub fn create_audio_device(sdl: &sdl2::Sdl) -> AudioDevice<NesAudioCallback> {
-    let audio_subsystem = sdl.audio().unwrap();
+const SAMPLE_RATE: i32 = 48_000;
+
+pub fn create_audio_device(audio_subsystem: &AudioSubsystem, channels: u8) -> AudioDevice<NesAudioCallback> {
     let audio_spec = AudioSpecDesired {
-        freq: Some(44_100),
-        channels: Some(1),
-        samples: Some(735), // Each frame should output 735 audio samples (44_100 / 60)
+        freq: Some(SAMPLE_RATE),
+        channels: Some(channels),
+        samples: Some((SAMPLE_RATE / 60) as u16), // About one frame of audio
     };
-    audio_subsystem.open_playback(None, &audio_spec, |_spec: AudioSpec| {
-        NesAudioCallback {
-            buffer: VecDeque::new(),
-            buffered_frames: 0,
-        }
-    }).unwrap()
+    let audio_device = audio_subsystem.open_playback(None, &audio_spec, |spec: AudioSpec| {
+        let capacity = (spec.freq as usize / 60) * spec.channels as usize * AUDIO_BUFFER_FRAMES;
+        let mut callback = NesAudioCallback {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+        };
+        callback.clear_samples();
+        callback
+    }).unwrap();
+    info!("Got audio device: {:?}", audio_device.spec());
+    audio_device
 }
 
 struct StdoutAndFileTarget {
@@ -12,13 +12,26 @@ mod divider;
 mod length_counter;
 mod linear_counter;
 mod dmc;
-
+mod filter;
+mod channel_output;
+mod resampler;
+mod stereo;
+mod recorder;
+mod register_log;
+
+use crate::apu::channel_output::{interleave_samples, OutputStream};
 use crate::apu::noise::Noise;
 use crate::apu::square::{SquareUnit, SquareWave};
 use crate::apu::triangle::TriangleWave;
 use crate::mapper;
 use crate::mapper::Mapper;
-use crate::nes::{CYCLES_PER_FRAME, InterruptSource, Signals};
+use crate::nes::{InterruptSource, Signals};
+
+pub use crate::apu::channel_output::{ChannelSamples, ChannelState};
+pub use crate::apu::filter::AudioFilterProfile;
+pub use crate::apu::recorder::{AudioRecorder, WavWriter};
+pub use crate::apu::register_log::{RegisterLog, RegisterWrite};
+pub use crate::apu::stereo::{ChannelMix, StereoMix};
 
 pub struct APU {
     square_wave1: SquareWave,
@@ -32,19 +45,34 @@ pub struct APU {
 
     irq_inhibit: bool,
     frame_counter_mode: FrameCountMode,
-
+    /// A $4017 write takes effect a few cycles later: the CPU cycle it happens on, and the new mode
+    pending_frame_counter_reset: Option<(u64, FrameCountMode)>,
+    /// CPU cycles since the frame counter was reset
+    frame_cycle: u64,
+    cpu_cycle: u64,
+
+    sample_rate: u32,
+    /// None for mono output
+    stereo_mix: Option<StereoMix>,
+    /// One stream for mono, or the left and right streams for stereo
+    output_streams: Vec<OutputStream>,
+    /// Scales the sample rate, from dynamic rate control
+    rate_adjustment: f64,
     mixed_samples: Vec<f32>,
-    cycles_between_samples: f64,
-    next_cycle_to_sample: u64,
-    sample_count: u64,
+    channel_samples: Option<ChannelSamples>,
+    /// Stands in for `channel_samples` while they're disabled
+    no_channel_samples: ChannelSamples,
 
-    apu_cycle: u64,
     signals: Rc<Signals>,
 
-    low_pass_filter: IIRFilter,
+    filter_profile: AudioFilterProfile,
+    register_log: Option<RegisterLog>,
+    /// The last value written to each register from $4000 to $4017, so a reset can write $4017
+    /// again and register logs can start from the current state
+    register_values: [Option<u8>; 0x18],
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum FrameCountMode {
     Step4,
     Step5,
@@ -60,8 +88,44 @@ bitflags! {
     }
 }
 
-const SAMPLES_PER_FRAME: u32 = 735;
-const SAMPLES_PER_SECOND: u32 = SAMPLES_PER_FRAME * 60;
+impl AudioChannels {
+    /// Every channel, one at a time.
+    pub const EACH: [AudioChannels; 5] = [AudioChannels::SQUARE1, AudioChannels::SQUARE2, AudioChannels::TRIANGLE, AudioChannels::NOISE, AudioChannels::DMC];
+}
+
+// Lookup table from https://www.nesdev.org/wiki/APU_Mixer
+static PULSE_OUT: [f32; 31] = {
+    let mut result = [0.0f32; 31];
+    let mut n = 0;
+    while n < result.len() {
+        result[n] = 95.52 / (8128.0 / (n as f32) + 100.0);
+        n += 1;
+    }
+    result
+};
+
+// Lookup table from https://www.nesdev.org/wiki/APU_Mixer
+static TND_OUT: [f32; 203] = {
+    let mut result = [0.0f32; 203];
+    let mut n = 0;
+    while n < result.len() {
+        result[n] = 163.67 / (24329.0 / (n as f32) + 100.0);
+        n += 1;
+    }
+    result
+};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// How far dynamic rate control can change the sample rate. Half a percent changes the pitch by
+/// less than a tenth of a semitone, which is too little to hear.
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+/// The NTSC CPU clock rate, the master clock (21.477272 MHz) divided by 12. Hosts that run the
+/// emulator at 60 frames a second rather than the NES's 60.0988 use dynamic rate control to absorb
+/// the difference.
+pub(crate) const CLOCK_RATE: f64 = 21_477_272.0 / 12.0;
+
 impl APU {
     pub fn new(mapper: Rc<Mapper>, signals: Rc<Signals>) -> APU {
         APU {
@@ -74,26 +138,37 @@ impl APU {
             host_enabled_channels: AudioChannels::all(),
 
             irq_inhibit: false,
-            frame_counter_mode: FrameCountMode::Step4,
 
-            mixed_samples: Vec::with_capacity(SAMPLES_PER_FRAME as usize),
-
-            cycles_between_samples: (CYCLES_PER_FRAME as f64 / SAMPLES_PER_FRAME as f64),
-            next_cycle_to_sample: 0,
-            sample_count: 0,
+            frame_counter_mode: FrameCountMode::Step4,
+            pending_frame_counter_reset: None,
+            frame_cycle: 0,
+            cpu_cycle: 0,
+
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            stereo_mix: None,
+            output_streams: vec![OutputStream::new(DEFAULT_SAMPLE_RATE, AudioFilterProfile::NesFrontLoader)],
+            rate_adjustment: 1.0,
+            mixed_samples: Vec::new(),
+            channel_samples: None,
+            no_channel_samples: ChannelSamples::empty(),
 
-            apu_cycle: 0,
             signals,
 
-            // The NES hardware follows the DACs with a surprisingly involved circuit that adds several low-pass and high-pass filters:
-            // - A first-order high-pass filter at 90 Hz
-            // - Another first-order high-pass filter at 440 Hz
-            // - A first-order low-pass filter at 14 kHz
-            low_pass_filter: IIRFilter::new(14_000.0, 1.0 / SAMPLES_PER_SECOND as f32),
+            // The NES hardware follows the DACs with a surprisingly involved circuit that adds several low-pass and high-pass filters
+            filter_profile: AudioFilterProfile::NesFrontLoader,
+            register_log: None,
+            register_values: [None; 0x18],
         }
     }
 
     pub fn step_cycle(&mut self, cpu_cycles: u64) {
+        self.cpu_cycle = cpu_cycles;
+        self.square_wave1.length_counter.start_cycle();
+        self.square_wave2.length_counter.start_cycle();
+        self.triangle_wave.length_counter.start_cycle();
+        self.noise.length_counter.start_cycle();
+        self.dmc.start_cycle();
+
         self.triangle_wave.tick();
 
         // All APU components other than triangle run at half the CPU clock rate, so skip them every other call.
@@ -102,51 +177,175 @@ impl APU {
             self.square_wave2.tick();
             self.noise.tick();
             self.dmc.tick();
+        }
+        self.step_frame_counter();
+
+        let levels = self.get_channel_levels();
+        let mix = match &self.stereo_mix {
+            Some(stereo_mix) => {
+                let mut enabled_levels = levels;
+                for (level, channel) in enabled_levels.iter_mut().zip(AudioChannels::EACH) {
+                    if !self.host_enabled_channels.contains(channel) {
+                        *level = 0;
+                    }
+                }
+                let (left, right) = stereo_mix.mix(enabled_levels);
+                [left, right]
+            }
+            None => {
+                let [pulse1, pulse2, triangle, noise, dmc] = levels;
+                [Self::mix_channels(pulse1, pulse2, triangle, noise, dmc, self.host_enabled_channels), 0.0]
+            }
+        };
+        for (stream, output) in self.output_streams.iter_mut().zip(mix) {
+            stream.add_input(output);
+        }
 
-            self.apu_cycle += 1;
+        if let Some(channel_samples) = self.channel_samples.as_mut() {
+            let [pulse1, pulse2, triangle, noise, dmc] = levels;
+            channel_samples.add_input(mix, [
+                PULSE_OUT[pulse1 as usize],
+                PULSE_OUT[pulse2 as usize],
+                TND_OUT[3 * triangle as usize],
+                TND_OUT[2 * noise as usize],
+                TND_OUT[dmc as usize],
+            ]);
+        }
+    }
 
-            // See https://www.nesdev.org/wiki/APU_Frame_Counter
-            match self.apu_cycle {
-                3728 => {
-                    self.tick_envelope_and_triangle();
-                }
-                7456 => {
-                    self.tick_envelope_and_triangle();
-                    self.tick_length_counters_and_sweep();
-                }
-                11185 => {
-                    self.tick_envelope_and_triangle();
-                }
-                14914 if self.frame_counter_mode == FrameCountMode::Step4 => {
-                    self.tick_envelope_and_triangle();
-                    self.tick_length_counters_and_sweep();
-                    self.trigger_irq();
-                    self.apu_cycle = 0;
-                }
-                18640 if self.frame_counter_mode == FrameCountMode::Step5 => {
+    /// Sets the rate that `output_samples` produces samples at. Any samples that haven't been
+    /// output yet are discarded.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.reset_output_streams();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_filter_profile(&mut self, profile: AudioFilterProfile) {
+        self.filter_profile = profile;
+        self.reset_output_streams();
+    }
+
+    pub fn filter_profile(&self) -> AudioFilterProfile {
+        self.filter_profile
+    }
+
+    /// Halts the triangle at ultrasonic periods, rather than letting it keep stepping like it does
+    /// on hardware. Off by default, but it can avoid pops in games that use them to silence it.
+    pub fn set_silence_ultrasonic_triangle(&mut self, silence: bool) {
+        self.triangle_wave.silence_ultrasonic = silence;
+    }
+
+    pub fn silence_ultrasonic_triangle(&self) -> bool {
+        self.triangle_wave.silence_ultrasonic
+    }
+
+    /// Switches to stereo output mixed by `stereo_mix`, or back to mono with None.
+    /// In stereo, `output_samples` outputs interleaved left and right samples.
+    pub fn set_stereo_mix(&mut self, stereo_mix: Option<StereoMix>) {
+        let was_stereo = self.stereo_mix.is_some();
+        self.stereo_mix = stereo_mix;
+        if self.stereo_mix.is_some() != was_stereo {
+            self.reset_output_streams();
+        }
+    }
+
+    pub fn stereo_mix(&self) -> Option<&StereoMix> {
+        self.stereo_mix.as_ref()
+    }
+
+    /// The number of interleaved channels in the output, 1 for mono or 2 for stereo.
+    pub fn output_channels(&self) -> u16 {
+        self.output_streams.len() as u16
+    }
+
+    fn reset_output_streams(&mut self) {
+        let num_streams = if self.stereo_mix.is_some() { 2 } else { 1 };
+        self.output_streams = (0..num_streams).map(|_| OutputStream::new(self.sample_rate, self.filter_profile)).collect();
+        self.mixed_samples.clear();
+        if self.channel_samples.is_some() {
+            self.set_channel_samples_enabled(true);
+        }
+        self.apply_rate_adjustment();
+    }
+
+    /// Dynamic rate control, for hosts that run the emulator in time with the display rather than
+    /// the audio device, which run at slightly different rates. The sample rate is nudged up when
+    /// the host's audio buffer is running low, and down when it's filling up, so it neither
+    /// underruns nor overflows.
+    /// Call this once a frame, with how full the buffer is, from 0.0 (empty) to 1.0 (full).
+    /// The rate is adjusted to keep it half full.
+    /// https://docs.libretro.com/development/cores/dynamic-rate-control/
+    pub fn adjust_rate_for_buffer_fill(&mut self, buffer_fill: f32) {
+        let buffer_fill = buffer_fill.clamp(0.0, 1.0) as f64;
+        self.rate_adjustment = 1.0 + (1.0 - 2.0 * buffer_fill) * MAX_RATE_ADJUSTMENT;
+        self.apply_rate_adjustment();
+    }
+
+    // The channel samples aren't adjusted, so they can be recorded at exactly the sample rate.
+    fn apply_rate_adjustment(&mut self) {
+        for stream in self.output_streams.iter_mut() {
+            stream.set_rate_adjustment(self.rate_adjustment);
+        }
+    }
+
+    // See https://www.nesdev.org/wiki/APU_Frame_Counter
+    // The steps are half way through APU cycles, so this counts CPU cycles.
+    fn step_frame_counter(&mut self) {
+        if let Some((reset_cycle, mode)) = self.pending_frame_counter_reset {
+            if self.cpu_cycle == reset_cycle {
+                self.pending_frame_counter_reset = None;
+                self.frame_counter_mode = mode;
+                self.frame_cycle = 0;
+                if mode == FrameCountMode::Step5 {
+                    // If the mode flag is set, then both "quarter frame" and "half frame" signals are also generated
                     self.tick_envelope_and_triangle();
                     self.tick_length_counters_and_sweep();
-                    self.apu_cycle = 0;
                 }
-                _ => {}
+                return;
             }
         }
 
-        if cpu_cycles >= self.next_cycle_to_sample {
-            self.record_sample();
+        self.frame_cycle += 1;
+        match (self.frame_cycle, self.frame_counter_mode) {
+            (7457, _) => {
+                self.tick_envelope_and_triangle();
+            }
+            (14913, _) => {
+                self.tick_envelope_and_triangle();
+                self.tick_length_counters_and_sweep();
+            }
+            (22371, _) => {
+                self.tick_envelope_and_triangle();
+            }
+            // The IRQ flag is set on three cycles in a row, so reading $4015 on the first two of
+            // them doesn't clear it for long.
+            (29828, FrameCountMode::Step4) => {
+                self.trigger_irq();
+            }
+            (29829, FrameCountMode::Step4) => {
+                self.tick_envelope_and_triangle();
+                self.tick_length_counters_and_sweep();
+                self.trigger_irq();
+            }
+            (29830, FrameCountMode::Step4) => {
+                self.trigger_irq();
+                self.frame_cycle = 0;
+            }
+            (37281, FrameCountMode::Step5) => {
+                self.tick_envelope_and_triangle();
+                self.tick_length_counters_and_sweep();
+            }
+            (37282, FrameCountMode::Step5) => {
+                self.frame_cycle = 0;
+            }
+            _ => {}
         }
     }
 
-    fn record_sample(&mut self) {
-        let mut sample: f32 = self.get_current_output();
-        sample = self.low_pass_filter.filter_sample(sample);
-        self.mixed_samples.push(sample);
-        self.sample_count += 1;
-
-        let next_cycle_to_sample: f64 = self.sample_count as f64 * self.cycles_between_samples;
-        self.next_cycle_to_sample = next_cycle_to_sample.round() as u64;
-    }
-
     fn tick_envelope_and_triangle(&mut self) {
         self.square_wave1.envelope.tick();
         self.square_wave2.envelope.tick();
@@ -168,15 +367,15 @@ impl APU {
         self.signals.request_interrupt(InterruptSource::APU_FRAME_COUNTER);
     }
 
-    fn get_current_output(&self) -> f32 {
-        Self::mix_channels(
+    /// Each channel's output level, in the same order as `AudioChannels::EACH`.
+    fn get_channel_levels(&self) -> [u8; 5] {
+        [
             self.square_wave1.get_current_output(),
             self.square_wave2.get_current_output(),
             self.triangle_wave.get_current_output(),
             self.noise.get_current_output(),
             self.dmc.get_current_output(),
-            self.host_enabled_channels,
-        )
+        ]
     }
 
     fn mix_channels(
@@ -193,28 +392,6 @@ impl APU {
         if !enabled.contains(AudioChannels::NOISE) { noise = 0; }
         if !enabled.contains(AudioChannels::DMC) { dmc = 0; }
 
-        // Lookup table from https://www.nesdev.org/wiki/APU_Mixer
-        static PULSE_OUT: [f32; 31] = {
-            let mut result = [0.0f32; 31];
-            let mut n = 0;
-            while n < result.len() {
-                result[n] = 95.52 / (8128.0 / (n as f32) + 100.0);
-                n += 1;
-            }
-            result
-        };
-
-        // Lookup table from https://www.nesdev.org/wiki/APU_Mixer
-        static TND_OUT: [f32; 203] = {
-            let mut result = [0.0f32; 203];
-            let mut n = 0;
-            while n < result.len() {
-                result[n] = 163.67 / (24329.0 / (n as f32) + 100.0);
-                n += 1;
-            }
-            result
-        };
-
         // Mixing formula from here: https://www.nesdev.org/wiki/APU_Mixer
         let pulse_out = PULSE_OUT[(pulse1 + pulse2) as usize];
         let tnd_out = TND_OUT[(3 * triangle + 2 * noise + dmc) as usize];
@@ -223,6 +400,19 @@ impl APU {
         output
     }
 
+    /// Whether the DMC is waiting for a DMA to fill its sample buffer.
+    pub fn is_dmc_dma_pending(&self) -> bool {
+        self.dmc.needs_memory_read()
+    }
+
+    /// The "get" cycle of a DMC DMA, when the sample byte is read.
+    pub fn do_dmc_dma(&mut self) {
+        let read = self.dmc.perform_memory_read();
+        if let (Some(register_log), Some((addr, value))) = (self.register_log.as_mut(), read) {
+            register_log.log_sample_read(addr, value);
+        }
+    }
+
     pub fn read_register(&mut self, addr: u16) -> u8 {
         match addr {
             0x4015 => {
@@ -266,6 +456,13 @@ impl APU {
     }
 
     pub fn write_register(&mut self, addr: u16, value: u8) {
+        if matches!(addr, 0x4000..=0x4013 | 0x4015 | 0x4017) {
+            self.register_values[(addr - 0x4000) as usize] = Some(value);
+            if let Some(register_log) = self.register_log.as_mut() {
+                register_log.log_write(self.cpu_cycle, addr, value);
+            }
+        }
+
         match addr {
             0x4000 => self.square_wave1.write_control(value),
             0x4001 => self.square_wave1.write_ramp(value),
@@ -310,12 +507,61 @@ impl APU {
 
     fn write_frame_counter(&mut self, value: u8) {
         self.irq_inhibit = value & 0x40 != 0;
-        self.frame_counter_mode = if value & 0x80 == 0 { FrameCountMode::Step4 } else { FrameCountMode::Step5 };
-        self.apu_cycle = 0;
-        if self.frame_counter_mode == FrameCountMode::Step5 {
-            // If the mode flag is set, then both "quarter frame" and "half frame" signals are also generated
-            self.tick_envelope_and_triangle();
-            self.tick_length_counters_and_sweep();
+        if self.irq_inhibit {
+            self.signals.acknowledge_interrupt(InterruptSource::APU_FRAME_COUNTER);
+        }
+
+        // The frame counter is reset 3 cycles after a write during an APU cycle, or 4 cycles after
+        // a write between APU cycles.
+        let mode = if value & 0x80 == 0 { FrameCountMode::Step4 } else { FrameCountMode::Step5 };
+        let delay = if self.cpu_cycle & 1 == 0 { 3 } else { 4 };
+        self.pending_frame_counter_reset = Some((self.cpu_cycle + delay, mode));
+    }
+
+    /// Pressing the console's reset button silences the channels as if $00 was written to $4015,
+    /// and resets the frame counter as if the last value written to $4017 was written again. Both
+    /// IRQ flags are cleared. https://www.nesdev.org/wiki/CPU_power_up_state#After_reset
+    pub fn reset(&mut self) {
+        self.write_status_register(0x00);
+        self.write_frame_counter(self.register_values[0x17].unwrap_or(0));
+        self.signals.acknowledge_interrupt(InterruptSource::APU_FRAME_COUNTER | InterruptSource::APU_DMC);
+    }
+
+    /// Starts logging every register write, replacing any log that was already started. The log
+    /// starts with the registers' current values, so it plays back from the same state.
+    pub fn start_register_log(&mut self) {
+        let mut initial_values = self.register_values;
+        // Don't restart a sample that has already finished
+        if let Some(status) = initial_values[0x15].as_mut() {
+            if !self.dmc.has_bytes_remaining() {
+                *status &= !AudioChannels::DMC.bits();
+            }
+        }
+        self.register_log = Some(RegisterLog::new(self.cpu_cycle, &initial_values));
+    }
+
+    /// Stops logging register writes, and returns the log.
+    pub fn stop_register_log(&mut self) -> Option<RegisterLog> {
+        self.register_log.take()
+    }
+
+    /// Also output the mix at exactly the sample rate and each channel's samples separately, see
+    /// `output_samples_with_channels`. They cost several times as much as the mix to generate, so
+    /// they're off by default.
+    pub fn set_channel_samples_enabled(&mut self, enabled: bool) {
+        self.channel_samples = enabled.then(|| ChannelSamples::new(self.sample_rate, self.filter_profile, self.output_channels()));
+    }
+
+    /// What a channel is currently playing. Polling this once a frame is enough for most
+    /// visualizations.
+    pub fn channel_state(&self, channel: AudioChannels) -> ChannelState {
+        match channel {
+            AudioChannels::SQUARE1 => self.square_wave1.channel_state(),
+            AudioChannels::SQUARE2 => self.square_wave2.channel_state(),
+            AudioChannels::TRIANGLE => self.triangle_wave.channel_state(),
+            AudioChannels::NOISE => self.noise.channel_state(),
+            AudioChannels::DMC => self.dmc.channel_state(),
+            _ => ChannelState::default(),
         }
     }
 
@@ -325,41 +571,318 @@ impl APU {
         info!("Toggled channel {channel:?} to {state}")
     }
 
+    /// Outputs the samples generated since the last call, at `sample_rate()`, interleaved if there's
+    /// more than one of `output_channels()`.
+    /// Frames aren't a whole number of samples, so the number of samples varies a little each frame.
     pub fn output_samples(&mut self, output: impl FnOnce(&[f32])) {
-        // TODO: Find out why some frames have one fewer or one more sample than they should.
-        // if self.mixed_samples.len() != SAMPLES_PER_FRAME as usize {
-        //     warn!("Expected {SAMPLES_PER_FRAME} samples, got {}", self.mixed_samples.len());
-        // }
-        output(&self.mixed_samples[..]);
+        self.output_samples_with_channels(|mixed, _| output(mixed));
+    }
+
+    /// Like `output_samples`, but also outputs each channel's samples, if they're enabled with
+    /// `set_channel_samples_enabled`. Otherwise the channels have no samples.
+    pub fn output_samples_with_channels(&mut self, output: impl FnOnce(&[f32], &ChannelSamples)) {
+        for stream in self.output_streams.iter_mut() {
+            stream.read_samples();
+        }
+        interleave_samples(&mut self.output_streams, &mut self.mixed_samples);
+        match self.channel_samples.as_mut() {
+            Some(channel_samples) => {
+                channel_samples.read_samples();
+                output(&self.mixed_samples[..], channel_samples);
+                channel_samples.clear();
+            }
+            None => output(&self.mixed_samples[..], &self.no_channel_samples),
+        }
         self.mixed_samples.clear();
     }
 }
 
-// https://en.wikipedia.org/wiki/Low-pass_filter#Simple_infinite_impulse_response_filter
-pub struct IIRFilter {
-    last_sample_output: f32,
-    cutoff_freq: f32,
-    sample_period: f32,
-    alpha: f32,
+#[cfg(test)]
+fn new_test_apu() -> (APU, Rc<Signals>) {
+    use crate::cartridge::{Cartridge, CHR};
+    use crate::mapper::MapperDescriptor;
+
+    let cart = Cartridge::test_cart(MapperDescriptor::NROM, vec![0; 0x8000], CHR::ROM(vec![0; 0x2000].into_boxed_slice()));
+    let signals = Signals::new();
+    let mapper = Rc::new(Mapper::new(cart, Rc::clone(&signals)));
+    (APU::new(mapper, Rc::clone(&signals)), signals)
+}
+
+#[cfg(test)]
+fn run_cycles(apu: &mut APU, cycles: u64) {
+    for _ in 0..cycles {
+        apu.step_cycle(apu.cpu_cycle + 1);
+    }
+}
+
+#[test]
+fn test_frame_counter_irq() {
+    let (mut apu, signals) = new_test_apu();
+    let irq_active = || signals.is_active(InterruptSource::APU_FRAME_COUNTER);
+
+    // A write on an APU cycle resets the frame counter 3 cycles later
+    run_cycles(&mut apu, 10);
+    apu.write_register(0x4017, 0x00);
+    run_cycles(&mut apu, 2);
+    assert_ne!(apu.frame_cycle, 0);
+    run_cycles(&mut apu, 1);
+    assert_eq!(apu.frame_cycle, 0);
+
+    run_cycles(&mut apu, 29827);
+    assert!(!irq_active());
+    run_cycles(&mut apu, 1);
+    assert!(irq_active());
+
+    // Reading $4015 clears the flag, but it's set again on the next cycle
+    assert_eq!(apu.read_register(0x4015) & 0x40, 0x40);
+    assert!(!irq_active());
+    run_cycles(&mut apu, 1);
+    assert!(irq_active());
+
+    // Setting the IRQ inhibit flag clears it, and a write between APU cycles takes 4 cycles
+    run_cycles(&mut apu, 1);
+    apu.write_register(0x4017, 0x40);
+    assert!(!irq_active());
+    run_cycles(&mut apu, 3);
+    assert_ne!(apu.frame_cycle, 0);
+    run_cycles(&mut apu, 1);
+    assert_eq!(apu.frame_cycle, 0);
+    run_cycles(&mut apu, 29830);
+    assert!(!irq_active());
+}
+
+#[test]
+fn test_reset() {
+    let (mut apu, signals) = new_test_apu();
+    apu.write_register(0x4015, 0x0F);
+    apu.write_register(0x4003, 0);
+    apu.write_register(0x4017, 0x80);
+    run_cycles(&mut apu, 1000);
+    signals.request_interrupt(InterruptSource::APU_FRAME_COUNTER | InterruptSource::APU_DMC);
+
+    // The channels are silenced, the IRQs cleared and the frame counter reset in 5-step mode
+    apu.reset();
+    assert!(!signals.is_any_active());
+    assert!(apu.square_wave1.length_counter.is_zero());
+    assert!(apu.pending_frame_counter_reset.is_some());
+    run_cycles(&mut apu, 4);
+    assert!(apu.pending_frame_counter_reset.is_none() && apu.frame_cycle <= 1);
+    assert_eq!(apu.frame_counter_mode, FrameCountMode::Step5);
+
+    // With the last write in 4-step mode, the frame IRQ comes a frame after the reset
+    apu.write_register(0x4017, 0x00);
+    run_cycles(&mut apu, 1000);
+    apu.reset();
+    assert!(!signals.is_active(InterruptSource::APU_FRAME_COUNTER));
+    run_cycles(&mut apu, 4 + 29828);
+    assert_eq!(apu.frame_counter_mode, FrameCountMode::Step4);
+    assert!(signals.is_active(InterruptSource::APU_FRAME_COUNTER));
 }
 
-impl IIRFilter {
-    pub fn new(cutoff_freq: f32, sample_period: f32) -> IIRFilter {
-        use std::f32::consts::PI;
-        let alpha = (2.0 * PI * sample_period * cutoff_freq) / (2.0 * PI * sample_period * cutoff_freq + 1.0);
-        println!("Alpha = {alpha}");
-        IIRFilter {
-            last_sample_output: 0.0,
-            cutoff_freq,
-            sample_period,
-            alpha,
+#[test]
+fn test_register_log_starts_from_current_state() {
+    let (mut apu, _signals) = new_test_apu();
+    apu.write_register(0x4000, 0xBF);
+    apu.write_register(0x4013, 0x01);
+    apu.write_register(0x4015, 0x11);
+    apu.write_register(0x4017, 0x40);
+    run_cycles(&mut apu, 100);
+    apu.start_register_log();
+    let writes: Vec<(u16, u8)> = apu.register_log.as_ref().unwrap().writes().iter().map(|write| (write.addr, write.value)).collect();
+    assert_eq!(writes, [(0x4000, 0xBF), (0x4013, 0x01), (0x4015, 0x11), (0x4017, 0x40)]);
+
+    // The DMC's reads go in the VGM, before the register writes
+    apu.do_dmc_dma();
+    let mut vgm = Vec::new();
+    apu.stop_register_log().unwrap().write_vgm(&mut vgm).unwrap();
+    assert_eq!(&vgm[0x100..0x10A], &[0x67, 0x66, 0xC2, 3, 0, 0, 0, 0x00, 0xC0, 0x00]);
+
+    // A finished sample isn't started again
+    while apu.dmc.has_bytes_remaining() {
+        if apu.is_dmc_dma_pending() {
+            apu.do_dmc_dma();
         }
+        run_cycles(&mut apu, 1);
     }
+    apu.start_register_log();
+    assert_eq!(apu.register_log.as_ref().unwrap().writes()[2].value, 0x01);
+}
 
-    // sample is in range [0.0, 1.0]
-    pub fn filter_sample(&mut self, input: f32) -> f32 {
-        let output = self.last_sample_output + self.alpha * (input - self.last_sample_output);
-        self.last_sample_output = output;
-        output
+#[test]
+fn test_length_counter_reload_race() {
+    let (mut apu, _signals) = new_test_apu();
+    apu.write_register(0x4015, 0x01);
+    apu.write_register(0x4000, 0x00);
+    apu.write_register(0x4003, 3 << 3); // A length of 2
+    apu.write_register(0x4017, 0x00);
+    run_cycles(&mut apu, 3 + 14913);
+
+    // The length counter was just clocked from 2 to 1, so a reload to 10 is ignored
+    apu.write_register(0x4003, 0 << 3);
+    assert!(!apu.square_wave1.length_counter.is_zero());
+    run_cycles(&mut apu, 29829 - 14913);
+    assert!(apu.square_wave1.length_counter.is_zero());
+}
+
+#[test]
+fn test_dynamic_rate_control() {
+    let (mut apu, _signals) = new_test_apu();
+    apu.set_sample_rate(48_000);
+    apu.set_channel_samples_enabled(true);
+    // Within a sample, as the resampler's position carries over between reads
+    let assert_samples = |apu: &mut APU, expected: i64| {
+        run_cycles(apu, CLOCK_RATE as u64);
+        apu.output_samples_with_channels(|samples, channels| {
+            let count = samples.len() as i64;
+            assert!((count - expected).abs() <= 1, "Expected {expected} samples, got {count}");
+            // The channel samples are for recording, so they aren't adjusted
+            let count = channels.mix().len() as i64;
+            assert!((count - 48_000).abs() <= 1, "Expected 48000 channel samples, got {count}");
+        });
+    };
+
+    assert_samples(&mut apu, 48_000);
+    // An empty buffer is filled faster, and a full one slower
+    apu.adjust_rate_for_buffer_fill(0.0);
+    assert_samples(&mut apu, 48_240);
+    apu.adjust_rate_for_buffer_fill(1.0);
+    assert_samples(&mut apu, 47_760);
+    apu.adjust_rate_for_buffer_fill(0.5);
+    assert_samples(&mut apu, 48_000);
+}
+
+/// Runs the APU for `cycles` CPU cycles, making each (CPU cycle, address, value) register write on
+/// its cycle, and returns the output as a 16-bit mono WAV file.
+#[cfg(test)]
+fn render_register_writes(apu: &mut APU, writes: &[(u64, u16, u8)], cycles: u64) -> Vec<u8> {
+    apu.set_filter_profile(AudioFilterProfile::Clean);
+    let mut samples = Vec::new();
+    for cycle in 0..cycles {
+        for (_, addr, value) in writes.iter().filter(|(write_cycle, ..)| *write_cycle == cycle) {
+            apu.write_register(*addr, *value);
+        }
+        if apu.is_dmc_dma_pending() {
+            apu.do_dmc_dma();
+        }
+        run_cycles(apu, 1);
     }
+    apu.output_samples(|output| samples.extend_from_slice(output));
+
+    let mut wav = WavWriter::new(std::io::Cursor::new(Vec::new()), apu.sample_rate(), 1).unwrap();
+    wav.write_samples(&samples).unwrap();
+    wav.finish().unwrap().into_inner()
+}
+
+/// Compares a rendering with its snapshot in `test_data/apu`. The snapshots were recorded by this
+/// emulator, not from hardware, so they only catch changes to the output; the channels' timing is
+/// checked against NESdev's reference rates by their own tests.
+/// Set `UPDATE_AUDIO_SNAPSHOTS=1` to write the snapshots instead, after checking the new output
+/// sounds right.
+#[cfg(test)]
+fn assert_matches_snapshot(name: &str, wav: &[u8]) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data/apu").join(format!("{name}.wav"));
+    if std::env::var_os("UPDATE_AUDIO_SNAPSHOTS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, wav).unwrap();
+        return;
+    }
+
+    let snapshot = std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+    assert_eq!(snapshot.len(), wav.len(), "{name}: length differs from the snapshot");
+    assert_eq!(snapshot[..44], wav[..44], "{name}: header differs from the snapshot");
+    // Allow for floating point differences between platforms in the resampler
+    let to_samples = |data: &[u8]| data[44..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect::<Vec<_>>();
+    for (i, (expected, actual)) in to_samples(&snapshot).iter().zip(to_samples(wav)).enumerate() {
+        assert!((*expected as i32 - actual as i32).abs() <= 4, "{name}: sample {i} is {actual}, expected {expected}");
+    }
+}
+
+/// 0.05 seconds
+#[cfg(test)]
+const SNAPSHOT_CYCLES: u64 = 89_490;
+
+#[test]
+fn test_triangle_ultrasonic_snapshot() {
+    let writes = [
+        (0, 0x4015, 0x04),
+        (0, 0x4008, 0xFF), // Linear counter on, no length counter
+        (0, 0x400A, 0xFD),
+        (0, 0x400B, 0x00), // Period 253, about 220 Hz
+        (20_000, 0x400A, 0x01), // Period 1, ultrasonic
+        (60_000, 0x400A, 0xFD),
+    ];
+
+    let (mut apu, _signals) = new_test_apu();
+    assert_matches_snapshot("triangle_ultrasonic", &render_register_writes(&mut apu, &writes, SNAPSHOT_CYCLES));
+
+    // Silenced, it holds its level while ultrasonic
+    let (mut apu, _signals) = new_test_apu();
+    apu.set_silence_ultrasonic_triangle(true);
+    let wav = render_register_writes(&mut apu, &writes[..5], 50_000);
+    let samples: Vec<_> = wav[44..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    let held = &samples[samples.len() - 400..];
+    assert!(held.iter().all(|sample| *sample == held[0]));
+}
+
+#[test]
+fn test_noise_snapshot() {
+    for (name, mode) in [("noise_long", 0x00), ("noise_short", 0x80)] {
+        let writes = [
+            (0, 0x4015, 0x08),
+            (0, 0x400C, 0x3F), // Constant volume 15, no length counter
+            (0, 0x400E, mode | 0x08),
+            (0, 0x400F, 0x00),
+        ];
+        let (mut apu, _signals) = new_test_apu();
+        assert_matches_snapshot(name, &render_register_writes(&mut apu, &writes, SNAPSHOT_CYCLES));
+    }
+}
+
+#[test]
+fn test_dmc_direct_load_snapshot() {
+    // The sample is all zeros, so the level steps down by 2 every 428 cycles until it reaches 0.
+    // It's 17 bytes, which finishes at about 58,000 cycles.
+    let writes = [
+        (0, 0x4011, 64),
+        (0, 0x4010, 0x00),
+        (0, 0x4012, 0x00),
+        (0, 0x4013, 0x01),
+        (0, 0x4015, 0x10),
+        (6_000, 0x4011, 100), // While playing: the deltas carry on from 100
+        (70_000, 0x4011, 40), // After it's finished: held at 40
+    ];
+    let (mut apu, _signals) = new_test_apu();
+    assert_matches_snapshot("dmc_direct_load", &render_register_writes(&mut apu, &writes, SNAPSHOT_CYCLES));
+}
+
+#[test]
+fn test_dmc_direct_load_while_playing() {
+    let (mut apu, _signals) = new_test_apu();
+    apu.write_register(0x4011, 64);
+    apu.write_register(0x4010, 0x0F); // Every 54 cycles
+    apu.write_register(0x4013, 0x01);
+    apu.write_register(0x4015, 0x10);
+    let run_until_output_clocked = |apu: &mut APU| {
+        loop {
+            let output_level = apu.dmc.get_current_output();
+            if apu.is_dmc_dma_pending() {
+                apu.do_dmc_dma();
+            }
+            run_cycles(apu, 1);
+            if apu.dmc.get_current_output() != output_level {
+                return;
+            }
+        }
+    };
+
+    // A write on the same cycle as the output unit's clock is lost
+    run_until_output_clocked(&mut apu);
+    apu.write_register(0x4011, 100);
+    assert_eq!(apu.dmc.get_current_output(), 62);
+    run_cycles(&mut apu, 1);
+    apu.write_register(0x4011, 100);
+    assert_eq!(apu.dmc.get_current_output(), 100);
+    // The sample is all zeros, so it steps down by 2, carrying on from the written level
+    run_until_output_clocked(&mut apu);
+    assert_eq!(apu.dmc.get_current_output(), 98);
 }
@@ -0,0 +1,154 @@
+use crate::apu::{AudioChannels, AudioFilterProfile, CLOCK_RATE};
+use crate::apu::filter::FilterChain;
+use crate::apu::resampler::Resampler;
+
+/// A snapshot of what a channel is playing, for visualizations like an oscilloscope or piano roll.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelState {
+    /// The period of the channel's timer, in the units the channel's registers use.
+    pub period: u32,
+    /// 0-15, or the output level (0-127) for the DMC. 0 when the channel is silenced.
+    pub volume: u8,
+    /// The pulse channels' duty cycle setting (0-3).
+    pub duty: Option<u8>,
+    /// The frequency in Hz, for the channels that play notes.
+    pub frequency: Option<f32>,
+}
+
+impl ChannelState {
+    /// The nearest MIDI note number to the channel's frequency (69 is A4, 440 Hz), with the
+    /// fractional part being how far out of tune it is.
+    pub fn note(&self) -> Option<f32> {
+        self.frequency.map(|frequency| 69.0 + 12.0 * (frequency / 440.0).log2())
+    }
+}
+
+/// The frequency of a channel whose timer clocks a sequence of `steps` steps every `period + 1`
+/// CPU cycles.
+pub(super) fn sequence_frequency(period: u32, steps: u32) -> f32 {
+    (CLOCK_RATE / (steps as f64 * (period as f64 + 1.0))) as f32
+}
+
+/// The mix and each channel's part of it, resampled and filtered separately from the output that's
+/// played, so they can be recorded, exported as stems or drawn as waveforms. They're always at
+/// exactly the sample rate, as dynamic rate control only applies to the played output.
+/// Unlike the mix, the channels muted by `APU::toggle_channel` are still included.
+pub struct ChannelSamples {
+    /// One stream for mono, or the left and right streams for stereo
+    mix_streams: Vec<OutputStream>,
+    mixed_samples: Vec<f32>,
+    streams: Vec<OutputStream>,
+}
+
+impl ChannelSamples {
+    pub(super) fn new(sample_rate: u32, filter_profile: AudioFilterProfile, output_channels: u16) -> ChannelSamples {
+        ChannelSamples {
+            mix_streams: (0..output_channels).map(|_| OutputStream::new(sample_rate, filter_profile)).collect(),
+            mixed_samples: Vec::new(),
+            streams: AudioChannels::EACH.iter().map(|_| OutputStream::new(sample_rate, filter_profile)).collect(),
+        }
+    }
+
+    /// No samples, for when the channel samples are disabled.
+    pub(super) fn empty() -> ChannelSamples {
+        ChannelSamples { mix_streams: Vec::new(), mixed_samples: Vec::new(), streams: Vec::new() }
+    }
+
+    /// Adds one CPU cycle of output: the mix, with only the first used for mono, and each channel,
+    /// in the same order as `AudioChannels::EACH`.
+    pub(super) fn add_input(&mut self, mix: [f32; 2], outputs: [f32; 5]) {
+        for (stream, output) in self.mix_streams.iter_mut().zip(mix) {
+            stream.add_input(output);
+        }
+        for (stream, output) in self.streams.iter_mut().zip(outputs) {
+            stream.add_input(output);
+        }
+    }
+
+    pub(super) fn read_samples(&mut self) {
+        for stream in self.mix_streams.iter_mut().chain(self.streams.iter_mut()) {
+            stream.read_samples();
+        }
+        interleave_samples(&mut self.mix_streams, &mut self.mixed_samples);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.mixed_samples.clear();
+        for stream in self.streams.iter_mut() {
+            stream.samples.clear();
+        }
+    }
+
+    /// The mix, interleaved like `APU::output_samples`, but at exactly the sample rate, so it
+    /// doesn't line up exactly with the played output while dynamic rate control is in use.
+    pub fn mix(&self) -> &[f32] {
+        &self.mixed_samples
+    }
+
+    /// The samples for one channel, which line up with `mix()`.
+    pub fn samples(&self, channel: AudioChannels) -> &[f32] {
+        match AudioChannels::EACH.iter().position(|c| *c == channel) {
+            Some(index) if index < self.streams.len() => &self.streams[index].samples,
+            _ => &[],
+        }
+    }
+}
+
+/// Moves the samples from one stream for mono, or two streams for stereo, to `output`, interleaving
+/// them for stereo.
+pub(super) fn interleave_samples(streams: &mut [OutputStream], output: &mut Vec<f32>) {
+    match streams {
+        [] => {}
+        [mono] => output.append(&mut mono.samples),
+        [left, right] => {
+            for (l, r) in left.samples.drain(..).zip(right.samples.drain(..)) {
+                output.push(l);
+                output.push(r);
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// One stream of output, resampled from the CPU clock rate then filtered.
+pub(super) struct OutputStream {
+    resampler: Resampler,
+    filters: FilterChain,
+    pub samples: Vec<f32>,
+}
+
+impl OutputStream {
+    pub fn new(sample_rate: u32, filter_profile: AudioFilterProfile) -> OutputStream {
+        OutputStream {
+            resampler: Resampler::new(CLOCK_RATE, sample_rate),
+            filters: FilterChain::new(filter_profile, sample_rate),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn add_input(&mut self, input: f32) {
+        self.resampler.add_input(input);
+    }
+
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.resampler.set_rate_adjustment(adjustment);
+    }
+
+    /// Appends the samples that are complete to `samples`.
+    pub fn read_samples(&mut self) {
+        let start = self.samples.len();
+        self.resampler.read_samples(&mut self.samples);
+        for sample in self.samples[start..].iter_mut() {
+            *sample = self.filters.filter_sample(*sample);
+        }
+    }
+}
+
+#[test]
+fn test_note() {
+    let a4 = ChannelState { frequency: Some(440.0), ..ChannelState::default() };
+    assert_eq!(a4.note(), Some(69.0));
+    let c5 = ChannelState { frequency: Some(523.2511), ..ChannelState::default() };
+    assert!((c5.note().unwrap() - 72.0).abs() < 0.001);
+    assert_eq!(ChannelState::default().note(), None);
+}
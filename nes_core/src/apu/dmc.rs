@@ -1,11 +1,12 @@
 use std::rc::Rc;
+use crate::apu::channel_output::ChannelState;
 use crate::mapper::Mapper;
 use crate::nes::{InterruptSource, Signals};
 
 pub struct DMC {
     irq_enabled: bool,
     loop_flag: bool,
-    rate: u32, //
+    rate: u32, // In CPU cycles
     timer: u32,
 
     // Output unit
@@ -13,6 +14,8 @@ pub struct DMC {
     bits_remaining: u8,
     output_level: u8, // 0-127
     silence: bool,
+    /// Whether the output unit changed the output level during the current CPU cycle
+    output_clocked_this_cycle: bool,
 
     sample_address: u16,
     sample_length: u32,
@@ -42,6 +45,7 @@ impl DMC {
             bits_remaining: 0,
             output_level: 0,
             silence: false,
+            output_clocked_this_cycle: false,
 
             sample_address: 0xC000,
             sample_length: 0,
@@ -57,18 +61,24 @@ impl DMC {
         dmc
     }
 
+    pub fn start_cycle(&mut self) {
+        self.output_clocked_this_cycle = false;
+    }
+
     pub fn tick(&mut self) {
         if self.timer != 0 {
             self.timer -= 1;
         } else {
             self.clock_output_unit();
-            self.timer = self.rate;
+            // The timer is clocked every APU cycle, which is two CPU cycles
+            self.timer = self.rate / 2 - 1;
         }
     }
 
     // https://www.nesdev.org/wiki/APU_DMC#Output_unit
     fn clock_output_unit(&mut self) {
         if !self.silence {
+            let output_level = self.output_level;
             if self.shift_register & 1 == 1 {
                 if self.output_level + 2 <= 127 {
                     self.output_level += 2;
@@ -78,6 +88,7 @@ impl DMC {
                     self.output_level -= 2;
                 }
             }
+            self.output_clocked_this_cycle = self.output_level != output_level;
         }
         self.shift_register >>= 1;
         self.bits_remaining -= 1;
@@ -93,23 +104,27 @@ impl DMC {
         if let Some(sample) = self.sample_buffer.take() {
             self.silence = false;
             self.shift_register = sample;
-            if self.reader_bytes_remaining > 0 {
-                self.perform_memory_read();
-            }
         } else {
             self.silence = true;
         }
     }
 
+    /// The memory reader refills the sample buffer as soon as it's emptied, using a DMA that
+    /// stalls the CPU.
+    pub fn needs_memory_read(&self) -> bool {
+        self.sample_buffer.is_none() && self.reader_bytes_remaining > 0
+    }
+
+    /// Returns the address and value read, if there was a byte left to read.
     // https://www.nesdev.org/wiki/APU_DMC#Memory_reader
-    fn perform_memory_read(&mut self) {
+    pub fn perform_memory_read(&mut self) -> Option<(u16, u8)> {
         if self.reader_bytes_remaining == 0 {
-            return;
+            return None;
         }
 
-        // TODO: Stall CPU
-
-        self.sample_buffer = Some(self.mapper.read_main_bus(self.reader_address_buffer));
+        let addr = self.reader_address_buffer;
+        let value = self.mapper.read_main_bus(addr);
+        self.sample_buffer = Some(value);
 
         if self.reader_address_buffer < 0xFFFF {
             self.reader_address_buffer += 1;
@@ -126,6 +141,7 @@ impl DMC {
                 self.signals.request_interrupt(InterruptSource::APU_DMC);
             }
         }
+        Some((addr, value))
     }
 
     fn restart_sample(&mut self) {
@@ -137,6 +153,15 @@ impl DMC {
         self.output_level
     }
 
+    pub fn channel_state(&self) -> ChannelState {
+        ChannelState {
+            period: self.rate,
+            volume: self.output_level,
+            duty: None,
+            frequency: None,
+        }
+    }
+
     pub fn set_channel_enabled(&mut self, enabled: bool) {
         if !enabled {
             // If the DMC bit is clear, the DMC bytes remaining will be set to 0 and the DMC will silence when it empties.
@@ -161,7 +186,15 @@ impl DMC {
         self.rate = DMC_RATE_PERIODS[rate_index];
     }
 
+    /// Sets the output level straight away, whether or not a sample is playing. The sample's
+    /// deltas carry on from the new level, which is how games play PCM audio through $4011.
+    /// A write on the same cycle as the output unit changes the level is lost, as the output
+    /// unit's update wins the race.
+    /// https://www.nesdev.org/wiki/APU_DMC#Output_unit
     pub fn write_direct_load(&mut self, value: u8) {
+        if self.output_clocked_this_cycle {
+            return;
+        }
         self.output_level = value & 0b111_1111;
     }
 
@@ -174,3 +207,31 @@ impl DMC {
         self.sample_length = (value as u32) * 16 + 1;
     }
 }
+
+#[test]
+fn test_rates() {
+    use crate::apu::CLOCK_RATE;
+
+    // The output unit's bit rates, from https://www.nesdev.org/wiki/APU_DMC
+    const REFERENCE_RATES_HZ: [f64; 16] = [
+        4181.71, 4709.93, 5264.04, 5593.04, 6257.95, 7046.35, 7919.35, 8363.42,
+        9419.86, 11186.1, 12604.0, 13982.6, 16884.6, 21306.8, 24858.0, 33143.9,
+    ];
+    const APU_CYCLES: u32 = 1_000_000;
+
+    for (rate_index, reference_rate) in REFERENCE_RATES_HZ.iter().enumerate() {
+        let (apu, _signals) = super::new_test_apu();
+        let mut dmc = apu.dmc;
+        dmc.write_control(rate_index as u8);
+        let mut clocks = 0;
+        for _ in 0..APU_CYCLES {
+            let bits_remaining = dmc.bits_remaining;
+            dmc.tick();
+            if dmc.bits_remaining != bits_remaining {
+                clocks += 1;
+            }
+        }
+        let rate = clocks as f64 * CLOCK_RATE / (APU_CYCLES as f64 * 2.0);
+        assert!((rate - reference_rate).abs() / reference_rate < 0.001, "Rate {rate_index}: {rate} Hz, expected {reference_rate} Hz");
+    }
+}
@@ -0,0 +1,130 @@
+use std::f32::consts::PI;
+
+/// The filters the console's audio output circuit applies after the DACs.
+/// https://www.nesdev.org/wiki/APU_Mixer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioFilterProfile {
+    /// The original front-loading NES:
+    /// - A first-order high-pass filter at 90 Hz
+    /// - Another first-order high-pass filter at 440 Hz
+    /// - A first-order low-pass filter at 14 kHz
+    NesFrontLoader,
+    /// The original Famicom, which only has one high-pass filter, at a much lower 37 Hz, so it has
+    /// more bass than the NES.
+    Famicom,
+    /// No filtering at all, the raw output of the DACs.
+    Clean,
+}
+
+impl AudioFilterProfile {
+    pub const ALL: [AudioFilterProfile; 3] = [AudioFilterProfile::NesFrontLoader, AudioFilterProfile::Famicom, AudioFilterProfile::Clean];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioFilterProfile::NesFrontLoader => "NES (Front-loader)",
+            AudioFilterProfile::Famicom => "Famicom",
+            AudioFilterProfile::Clean => "Clean (No Filter)",
+        }
+    }
+}
+
+/// A chain of first-order filters, applied in order.
+pub(super) struct FilterChain {
+    filters: Vec<IIRFilter>,
+}
+
+impl FilterChain {
+    pub fn new(profile: AudioFilterProfile, sample_rate: u32) -> FilterChain {
+        let sample_period = 1.0 / sample_rate as f32;
+        let filters = match profile {
+            AudioFilterProfile::NesFrontLoader => vec![
+                IIRFilter::high_pass(90.0, sample_period),
+                IIRFilter::high_pass(440.0, sample_period),
+                IIRFilter::low_pass(14_000.0, sample_period),
+            ],
+            AudioFilterProfile::Famicom => vec![
+                IIRFilter::high_pass(37.0, sample_period),
+                IIRFilter::low_pass(14_000.0, sample_period),
+            ],
+            AudioFilterProfile::Clean => vec![],
+        };
+        FilterChain { filters }
+    }
+
+    pub fn filter_sample(&mut self, input: f32) -> f32 {
+        self.filters.iter_mut().fold(input, |sample, filter| filter.filter_sample(sample))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FilterKind {
+    LowPass,
+    HighPass,
+}
+
+// https://en.wikipedia.org/wiki/Low-pass_filter#Simple_infinite_impulse_response_filter
+// https://en.wikipedia.org/wiki/High-pass_filter#Algorithmic_implementation
+struct IIRFilter {
+    kind: FilterKind,
+    alpha: f32,
+    last_sample_input: f32,
+    last_sample_output: f32,
+}
+
+impl IIRFilter {
+    fn low_pass(cutoff_freq: f32, sample_period: f32) -> IIRFilter {
+        let rc = 1.0 / (2.0 * PI * cutoff_freq);
+        IIRFilter::new(FilterKind::LowPass, sample_period / (rc + sample_period))
+    }
+
+    fn high_pass(cutoff_freq: f32, sample_period: f32) -> IIRFilter {
+        let rc = 1.0 / (2.0 * PI * cutoff_freq);
+        IIRFilter::new(FilterKind::HighPass, rc / (rc + sample_period))
+    }
+
+    fn new(kind: FilterKind, alpha: f32) -> IIRFilter {
+        IIRFilter {
+            kind,
+            alpha,
+            last_sample_input: 0.0,
+            last_sample_output: 0.0,
+        }
+    }
+
+    fn filter_sample(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::LowPass => self.last_sample_output + self.alpha * (input - self.last_sample_output),
+            FilterKind::HighPass => self.alpha * (self.last_sample_output + input - self.last_sample_input),
+        };
+        self.last_sample_input = input;
+        self.last_sample_output = output;
+        output
+    }
+}
+
+#[test]
+fn test_filter_chain() {
+    // The high-pass filters remove the DC offset
+    let mut nes = FilterChain::new(AudioFilterProfile::NesFrontLoader, 48_000);
+    let mut output = 0.0;
+    for _ in 0..48_000 {
+        output = nes.filter_sample(0.5);
+    }
+    assert!(output.abs() < 0.001);
+
+    // The Famicom's filter lets more of a low frequency through than the NES's
+    let amplitude = |profile: AudioFilterProfile| {
+        let mut chain = FilterChain::new(profile, 48_000);
+        let mut max: f32 = 0.0;
+        for i in 0..48_000 {
+            let input = (2.0 * PI * 100.0 * i as f32 / 48_000.0).sin();
+            let output = chain.filter_sample(input);
+            if i > 24_000 {
+                max = max.max(output.abs());
+            }
+        }
+        max
+    };
+    assert!(amplitude(AudioFilterProfile::Famicom) > amplitude(AudioFilterProfile::NesFrontLoader));
+    assert!((amplitude(AudioFilterProfile::Clean) - 1.0).abs() < 0.001);
+}
@@ -2,6 +2,8 @@ pub struct LengthCounter {
     length: u8,
     pub halt: bool,
     channel_enabled: bool,
+    /// Whether the counter was clocked during the current CPU cycle
+    clocked_this_cycle: bool,
 }
 
 const LENGTH_LUT: [u8; 32] = [
@@ -15,6 +17,7 @@ impl LengthCounter {
             length: 0,
             halt: true,
             channel_enabled: true,
+            clocked_this_cycle: false,
         }
     }
 
@@ -29,7 +32,18 @@ impl LengthCounter {
         }
     }
 
+    pub fn start_cycle(&mut self) {
+        self.clocked_this_cycle = false;
+    }
+
     pub fn set_value(&mut self, index: u8) {
+        // A reload on the same cycle as the counter is clocked is ignored, unless the counter was
+        // already 0 (so the clock didn't change it).
+        // A write to the halt flag on the same cycle takes effect after the clock, which happens
+        // naturally, as the APU is stepped before the CPU's write.
+        if self.clocked_this_cycle {
+            return;
+        }
         if self.channel_enabled {
             self.length = LENGTH_LUT[index as usize];
         } else {
@@ -47,6 +61,7 @@ impl LengthCounter {
 
         if self.length > 0 {
             self.length -= 1;
+            self.clocked_this_cycle = true;
         }
     }
 }
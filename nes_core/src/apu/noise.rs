@@ -1,8 +1,10 @@
+use crate::apu::channel_output::ChannelState;
 use crate::apu::envelope::Envelope;
 use crate::apu::length_counter::LengthCounter;
 
 /// https://www.nesdev.org/wiki/APU_Noise
 pub struct Noise {
+    /// In CPU cycles
     period: u32,
     timer: u32,
     feedback_bit_6: bool,
@@ -57,18 +59,30 @@ impl Noise {
         volume
     }
 
+    pub fn channel_state(&self) -> ChannelState {
+        ChannelState {
+            period: self.period,
+            volume: if self.length_counter.is_zero() { 0 } else { self.envelope.get_volume() },
+            duty: None,
+            frequency: None,
+        }
+    }
+
     pub fn tick(&mut self) {
         if self.timer != 0 {
             self.timer -= 1;
         } else {
             self.clock_shift_register();
-            self.timer = self.period;
+            // The timer is clocked every APU cycle, which is two CPU cycles
+            self.timer = self.period / 2 - 1;
         }
     }
 
     fn clock_shift_register(&mut self) {
         let mut sr = self.shift_register;
 
+        // Mode flag set: the feedback comes from bit 6 rather than bit 1, which gives a sequence
+        // 93 (or 31) steps long instead of 32767, so it sounds metallic or buzzy.
         let shift_amt = if self.feedback_bit_6 { 6 } else { 1 };
         let feedback = (sr & 1) ^ (sr >> shift_amt & 1);
 
@@ -79,3 +93,35 @@ impl Noise {
         self.shift_register = sr;
     }
 }
+
+#[test]
+fn test_shift_register() {
+    // The timer is in CPU cycles, so the fastest period clocks the shift register every other APU cycle
+    let mut noise = Noise::new();
+    noise.write_noise_freq1(0x00);
+    let mut clocks = 0;
+    for _ in 0..100 {
+        let shift_register = noise.shift_register;
+        noise.tick();
+        if noise.shift_register != shift_register {
+            clocks += 1;
+        }
+    }
+    assert_eq!(clocks, 50);
+
+    let sequence_length = |mode: u8| {
+        let mut noise = Noise::new();
+        noise.write_noise_freq1(mode);
+        let start = noise.shift_register;
+        let mut length = 0;
+        loop {
+            noise.clock_shift_register();
+            length += 1;
+            if noise.shift_register == start {
+                return length;
+            }
+        }
+    };
+    assert_eq!(sequence_length(0x00), 32767);
+    assert_eq!(sequence_length(0x80), 93);
+}
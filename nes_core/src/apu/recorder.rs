@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::info;
+use crate::apu::{APU, AudioChannels, ChannelSamples};
+
+/// Records the APU's output to WAV files, optionally with a separate file for each channel.
+/// Pass it the channel samples from `APU::output_samples_with_channels`, which are at exactly the
+/// sample rate, unlike the played output while dynamic rate control is in use. The APU only
+/// produces those while recording, from `start` until `finish`.
+pub struct AudioRecorder {
+    mix: WavWriter<BufWriter<File>>,
+    stems: Vec<(AudioChannels, WavWriter<BufWriter<File>>)>,
+}
+
+impl AudioRecorder {
+    /// Starts recording the mix to `path`. With `record_stems`, each channel is also recorded to a
+    /// file next to it, e.g. `recording.wav` has `recording_pulse1.wav`.
+    pub fn start(path: &Path, apu: &mut APU, record_stems: bool) -> io::Result<AudioRecorder> {
+        let mix = WavWriter::new(create_file(path)?, apu.sample_rate(), apu.output_channels())?;
+        apu.set_channel_samples_enabled(true);
+        let mut stems = Vec::new();
+        if record_stems {
+            for channel in AudioChannels::EACH {
+                let stem_path = stem_path(path, channel);
+                stems.push((channel, WavWriter::new(create_file(&stem_path)?, apu.sample_rate(), 1)?));
+            }
+        }
+        info!("Started recording audio to {}", path.display());
+        Ok(AudioRecorder { mix, stems })
+    }
+
+    pub fn record_samples(&mut self, channels: &ChannelSamples) -> io::Result<()> {
+        self.mix.write_samples(channels.mix())?;
+        for (channel, stem) in self.stems.iter_mut() {
+            stem.write_samples(channels.samples(*channel))?;
+        }
+        Ok(())
+    }
+
+    /// Finishes writing the files, and stops the APU producing channel samples for them.
+    /// They aren't valid WAV files until this is called.
+    pub fn finish(self, apu: &mut APU) -> io::Result<()> {
+        apu.set_channel_samples_enabled(false);
+        self.mix.finish()?;
+        for (_, stem) in self.stems {
+            stem.finish()?;
+        }
+        info!("Finished recording audio");
+        Ok(())
+    }
+}
+
+fn create_file(path: &Path) -> io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+fn stem_path(path: &Path, channel: AudioChannels) -> PathBuf {
+    let stem_name = match channel {
+        AudioChannels::SQUARE1 => "pulse1",
+        AudioChannels::SQUARE2 => "pulse2",
+        AudioChannels::TRIANGLE => "triangle",
+        AudioChannels::NOISE => "noise",
+        _ => "dmc",
+    };
+    let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{file_stem}_{stem_name}.wav"))
+}
+
+/// Writes 16-bit PCM WAV files.
+/// http://soundfile.sapp.org/doc/WaveFormat/
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_bytes: u32,
+}
+
+const HEADER_BYTES: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?; // Filled in by finish()
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?; // Filled in by finish()
+        Ok(WavWriter { writer, data_bytes: 0 })
+    }
+
+    /// Writes samples in the range [-1.0, 1.0], interleaved if there's more than one channel.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    /// Fills in the sizes in the header, now that they're known.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_BYTES - 8 + self.data_bytes).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[test]
+fn test_wav_writer() {
+    let mut wav = WavWriter::new(io::Cursor::new(Vec::new()), 48_000, 2).unwrap();
+    wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+    let data = wav.finish().unwrap().into_inner();
+
+    let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    assert_eq!(&data[0..4], b"RIFF");
+    assert_eq!(read_u32(4) as usize, data.len() - 8);
+    assert_eq!(read_u32(24), 48_000);
+    assert_eq!(read_u32(28), 48_000 * 4);
+    assert_eq!(read_u32(40), 8);
+    assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+
+    assert_eq!(stem_path(Path::new("/tmp/song.wav"), AudioChannels::TRIANGLE), Path::new("/tmp/song_triangle.wav"));
+}
+
+#[test]
+fn test_recording_enables_channel_samples() {
+    let (mut apu, _) = super::new_test_apu();
+    let path = std::env::temp_dir().join(format!("nes_core_test_recording_{}.wav", std::process::id()));
+    let recorder = AudioRecorder::start(&path, &mut apu, false).unwrap();
+    assert!(apu.channel_samples.is_some());
+    recorder.finish(&mut apu).unwrap();
+    assert!(apu.channel_samples.is_none());
+    std::fs::remove_file(&path).unwrap();
+}
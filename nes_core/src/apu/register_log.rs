@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use crate::apu::CLOCK_RATE;
+
+/// VGM players clock the APU at the CPU clock rate, in whole Hz.
+const NTSC_CPU_CLOCK: u64 = CLOCK_RATE as u64;
+/// VGM files time everything in samples at 44.1 kHz.
+const VGM_SAMPLE_RATE: u64 = 44_100;
+const VGM_HEADER_BYTES: usize = 0x100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterWrite {
+    pub cpu_cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// Every write to the APU's registers ($4000-$4013, $4015 and $4017), with the CPU cycle it
+/// happened on, so the music can be exported and played back elsewhere. The DMC's sample bytes
+/// are kept too, as it reads them from the cartridge rather than having them written.
+pub struct RegisterLog {
+    start_cycle: u64,
+    writes: Vec<RegisterWrite>,
+    /// Every byte the DMC has read, by address
+    sample_data: BTreeMap<u16, u8>,
+    /// Set if the DMC read different bytes from the same address, e.g. after a bank switch
+    sample_data_changed: bool,
+}
+
+impl RegisterLog {
+    /// Starts a log with writes of the registers' values when it starts, indexed from $4000, so
+    /// the music plays back from the same state. The channel registers come first, while playback
+    /// still has the channels disabled, so their length counters don't restart old notes.
+    pub(super) fn new(start_cycle: u64, initial_values: &[Option<u8>; 0x18]) -> RegisterLog {
+        let mut log = RegisterLog {
+            start_cycle,
+            writes: Vec::new(),
+            sample_data: BTreeMap::new(),
+            sample_data_changed: false,
+        };
+        let channel_registers = 0x4000..=0x4013;
+        for addr in channel_registers.chain([0x4015, 0x4017]) {
+            if let Some(value) = initial_values[(addr - 0x4000) as usize] {
+                log.log_write(start_cycle, addr, value);
+            }
+        }
+        log
+    }
+
+    pub(super) fn log_write(&mut self, cpu_cycle: u64, addr: u16, value: u8) {
+        self.writes.push(RegisterWrite { cpu_cycle, addr, value });
+    }
+
+    pub(super) fn log_sample_read(&mut self, addr: u16, value: u8) {
+        if let Some(previous) = self.sample_data.insert(addr, value) {
+            self.sample_data_changed |= previous != value;
+        }
+    }
+
+    pub fn writes(&self) -> &[RegisterWrite] {
+        &self.writes
+    }
+
+    /// Writes the log as CSV, one write per line, with the address and value in hex.
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "cpu_cycle,address,value")?;
+        for write in &self.writes {
+            writeln!(writer, "{},${:04X},${:02X}", write.cpu_cycle, write.addr, write.value)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the log as a VGM file for the NES APU, which many music players and trackers can
+    /// import. The DMC's samples are loaded into the player's memory up front, so it fails if
+    /// they changed during the log, which VGM can only handle by reloading them mid-song.
+    /// https://vgmrips.net/wiki/VGM_Specification
+    pub fn write_vgm(&self, mut writer: impl Write) -> io::Result<()> {
+        if self.sample_data_changed {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "The DMC samples changed during the log, probably from bank switching, which VGM export doesn't support"));
+        }
+
+        let mut data = Vec::new();
+        write_vgm_sample_data(&mut data, &self.sample_data);
+        let mut samples_written = 0u64;
+        for write in &self.writes {
+            let sample = (write.cpu_cycle - self.start_cycle) * VGM_SAMPLE_RATE / NTSC_CPU_CLOCK;
+            write_vgm_wait(&mut data, sample - samples_written);
+            samples_written = sample;
+            // NES APU write: the register's offset from $4000, then the value
+            data.extend_from_slice(&[0xB4, (write.addr - 0x4000) as u8, write.value]);
+        }
+        data.push(0x66); // End of sound data
+
+        let mut header = [0u8; VGM_HEADER_BYTES];
+        let mut set_u32 = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        set_u32(0x04, (VGM_HEADER_BYTES + data.len() - 0x04) as u32); // Offset of the end of the file
+        set_u32(0x08, 0x0000_0161); // Version 1.61, the first with the NES APU
+        set_u32(0x18, samples_written as u32); // Total samples
+        set_u32(0x24, 60); // Rate
+        set_u32(0x34, (VGM_HEADER_BYTES - 0x34) as u32); // Offset of the data
+        set_u32(0x84, NTSC_CPU_CLOCK as u32); // NES APU clock
+        header[0..4].copy_from_slice(b"Vgm ");
+
+        writer.write_all(&header)?;
+        writer.write_all(&data)
+    }
+}
+
+/// Writes each run of consecutive addresses as a data block that writes it to the player's
+/// memory, for the DMC to read from.
+fn write_vgm_sample_data(data: &mut Vec<u8>, sample_data: &BTreeMap<u16, u8>) {
+    let mut runs: Vec<(u16, Vec<u8>)> = Vec::new();
+    for (&addr, &value) in sample_data {
+        match runs.last_mut() {
+            Some((start, bytes)) if *start as usize + bytes.len() == addr as usize => bytes.push(value),
+            _ => runs.push((addr, vec![value])),
+        }
+    }
+    for (start, bytes) in runs {
+        // Data block of type $C2, a write to NES APU memory: the size, then the start address and the bytes
+        data.extend_from_slice(&[0x67, 0x66, 0xC2]);
+        data.extend_from_slice(&(bytes.len() as u32 + 2).to_le_bytes());
+        data.extend_from_slice(&start.to_le_bytes());
+        data.extend_from_slice(&bytes);
+    }
+}
+
+fn write_vgm_wait(data: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        match samples {
+            1..=16 => {
+                data.push(0x70 + (samples - 1) as u8);
+                samples = 0;
+            }
+            735 => {
+                data.push(0x62); // One 60 Hz frame
+                samples = 0;
+            }
+            _ => {
+                let wait = samples.min(u16::MAX as u64);
+                data.push(0x61);
+                data.extend_from_slice(&(wait as u16).to_le_bytes());
+                samples -= wait;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_register_log_export() {
+    let mut log = RegisterLog::new(1000, &[None; 0x18]);
+    log.log_write(1000, 0x4015, 0x0F);
+    log.log_write(1000 + 29830, 0x4000, 0xBF); // 735 samples later
+    log.log_write(1000 + NTSC_CPU_CLOCK, 0x4003, 0x08);
+
+    let mut csv = Vec::new();
+    log.write_csv(&mut csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap(), "cpu_cycle,address,value\n1000,$4015,$0F\n30830,$4000,$BF\n1790772,$4003,$08\n");
+
+    let mut vgm = Vec::new();
+    log.write_vgm(&mut vgm).unwrap();
+    let read_u32 = |offset: usize| u32::from_le_bytes(vgm[offset..offset + 4].try_into().unwrap());
+    assert_eq!(&vgm[0..4], b"Vgm ");
+    assert_eq!(read_u32(0x04) as usize, vgm.len() - 4);
+    assert_eq!(read_u32(0x18), 44_100);
+    assert_eq!(read_u32(0x84), 1_789_772);
+    assert_eq!(&vgm[VGM_HEADER_BYTES..], &[
+        0xB4, 0x15, 0x0F,
+        0x62,
+        0xB4, 0x00, 0xBF,
+        0x61, 0x65, 0xA9, // 43,365 samples
+        0xB4, 0x03, 0x08,
+        0x66,
+    ]);
+}
+
+#[test]
+fn test_register_log_initial_values_and_samples() {
+    let mut initial_values = [None; 0x18];
+    initial_values[0x17] = Some(0x40);
+    initial_values[0x15] = Some(0x1F);
+    initial_values[0x03] = Some(0x08);
+    initial_values[0x00] = Some(0xBF);
+    let mut log = RegisterLog::new(1000, &initial_values);
+    let addrs: Vec<u16> = log.writes().iter().map(|write| write.addr).collect();
+    assert_eq!(addrs, [0x4000, 0x4003, 0x4015, 0x4017]);
+    assert!(log.writes().iter().all(|write| write.cpu_cycle == 1000));
+
+    // Consecutive sample bytes are loaded in one block, read again or not
+    for (addr, value) in [(0xC000, 0x11), (0xC001, 0x22), (0xC000, 0x11), (0xFFFF, 0x33)] {
+        log.log_sample_read(addr, value);
+    }
+    let mut vgm = Vec::new();
+    log.write_vgm(&mut vgm).unwrap();
+    assert_eq!(&vgm[VGM_HEADER_BYTES..VGM_HEADER_BYTES + 24], &[
+        0x67, 0x66, 0xC2, 4, 0, 0, 0, 0x00, 0xC0, 0x11, 0x22,
+        0x67, 0x66, 0xC2, 3, 0, 0, 0, 0xFF, 0xFF, 0x33,
+        0xB4, 0x00, 0xBF,
+    ]);
+
+    // A sample that changes can't be exported
+    log.log_sample_read(0xC001, 0x44);
+    assert!(log.write_vgm(&mut Vec::new()).is_err());
+}
@@ -0,0 +1,132 @@
+use std::f64::consts::PI;
+
+/// How many output samples each band-limited step is spread over.
+const KERNEL_WIDTH: usize = 16;
+/// How many positions between two output samples a step can be placed at.
+const KERNEL_PHASES: usize = 64;
+/// The cutoff frequency, as a fraction of the output sample rate. It's a little below the Nyquist
+/// frequency (0.5) so the kernel's transition band doesn't alias.
+const CUTOFF: f64 = 0.45;
+
+/// Converts the APU's output, which changes in steps on CPU cycles, to any output sample rate.
+/// Point sampling those steps aliases everything above the Nyquist frequency back down into the
+/// audible range, which is very noticeable on high pulses and the noise channel. Instead, each
+/// change in the output is added as a band-limited step: a windowed sinc impulse, which is
+/// integrated as the samples are read out.
+/// http://www.slack.net/~ant/bl-synth/
+pub(super) struct Resampler {
+    /// Output samples per input clock
+    ratio: f64,
+    /// The ratio before any rate adjustment
+    base_ratio: f64,
+    /// The time of the next input clock, in output samples from the start of `deltas`
+    time: f64,
+    kernel: Box<[[f32; KERNEL_WIDTH]; KERNEL_PHASES]>,
+    /// The difference between each output sample and the one before it
+    deltas: Vec<f32>,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Resampler {
+        let ratio = sample_rate as f64 / clock_rate;
+        Resampler {
+            ratio,
+            base_ratio: ratio,
+            time: 0.0,
+            kernel: make_kernel(),
+            deltas: vec![0.0; KERNEL_WIDTH],
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    /// Scales the output sample rate, e.g. 1.01 makes 1% more samples.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.ratio = self.base_ratio * adjustment;
+    }
+
+    /// Adds the input for one clock.
+    pub fn add_input(&mut self, input: f32) {
+        if input != self.last_input {
+            self.add_step(input - self.last_input);
+            self.last_input = input;
+        }
+        self.time += self.ratio;
+    }
+
+    fn add_step(&mut self, delta: f32) {
+        let index = self.time as usize;
+        let phase = ((self.time - index as f64) * KERNEL_PHASES as f64) as usize;
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (d, k) in self.deltas[index..index + KERNEL_WIDTH].iter_mut().zip(self.kernel[phase].iter()) {
+            *d += delta * k;
+        }
+    }
+
+    /// Appends all the output samples that are complete to `output`.
+    pub fn read_samples(&mut self, output: &mut Vec<f32>) {
+        let num_samples = self.time as usize;
+        // Without any steps, the deltas can be shorter than the time that's passed
+        self.deltas.resize(self.deltas.len().max(num_samples + KERNEL_WIDTH), 0.0);
+        for delta in self.deltas.drain(..num_samples) {
+            self.last_output += delta;
+            output.push(self.last_output);
+        }
+        self.time -= num_samples as f64;
+    }
+}
+
+/// A Blackman-windowed sinc impulse at each phase, normalized so every step has the same height.
+fn make_kernel() -> Box<[[f32; KERNEL_WIDTH]; KERNEL_PHASES]> {
+    let half_width = KERNEL_WIDTH as f64 / 2.0;
+    let mut kernel = Box::new([[0.0; KERNEL_WIDTH]; KERNEL_PHASES]);
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let offset = phase as f64 / KERNEL_PHASES as f64;
+        let mut values = [0.0f64; KERNEL_WIDTH];
+        for (k, value) in values.iter_mut().enumerate() {
+            let x = k as f64 - half_width - offset;
+            let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x) };
+            let window = if x.abs() < half_width {
+                0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos()
+            } else {
+                0.0
+            };
+            *value = sinc * window;
+        }
+        let sum: f64 = values.iter().sum();
+        for (tap, value) in taps.iter_mut().zip(values.iter()) {
+            *tap = (value / sum) as f32;
+        }
+    }
+    kernel
+}
+
+#[test]
+fn test_resampler() {
+    const CLOCK_RATE: f64 = 1_789_773.0;
+    let mut resampler = Resampler::new(CLOCK_RATE, 48_000);
+
+    // One second of input makes one second of output, and a step settles at its height
+    let mut output = Vec::new();
+    for _ in 0..CLOCK_RATE as usize {
+        resampler.add_input(0.5);
+    }
+    resampler.read_samples(&mut output);
+    assert!((47_999..=48_000).contains(&output.len()));
+    assert!((output.last().unwrap() - 0.5).abs() < 0.001);
+
+    // A square wave above the Nyquist frequency is filtered out, rather than aliasing
+    output.clear();
+    let half_period = (CLOCK_RATE / 30_000.0 / 2.0) as usize;
+    for i in 0..CLOCK_RATE as usize / 10 {
+        resampler.add_input(if (i / half_period) & 1 == 0 { 0.0 } else { 1.0 });
+    }
+    resampler.read_samples(&mut output);
+    let settled = &output[100..];
+    let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+    assert!(settled.iter().all(|sample| (sample - mean).abs() < 0.05));
+}
@@ -1,3 +1,4 @@
+use crate::apu::channel_output::{ChannelState, sequence_frequency};
 use crate::apu::envelope::Envelope;
 use crate::apu::length_counter::LengthCounter;
 use crate::apu::sweep::Sweep;
@@ -6,6 +7,7 @@ pub struct SquareWave {
     timer: u32,
     duty_cycle_pos: u8,
     duty_cycle_mask: u8,
+    duty: u8,
     period: u32,
     pub envelope: Envelope,
     pub sweep: Sweep,
@@ -25,6 +27,7 @@ impl SquareWave {
             timer: 0,
             duty_cycle_pos: 1,
             duty_cycle_mask: 0b00000001,
+            duty: 0,
             period: 0, // Range: 0-0x7FF / 0-2047 / 12.428KHz-54Hz
             envelope: Envelope::new(),
             sweep: Sweep::new(ones_complement),
@@ -48,6 +51,18 @@ impl SquareWave {
         volume
     }
 
+    pub fn channel_state(&self) -> ChannelState {
+        let audible = !self.length_counter.is_zero() && !self.sweep.should_mute(self.period);
+        let volume = if audible { self.envelope.get_volume() } else { 0 };
+        ChannelState {
+            period: self.period,
+            volume,
+            duty: Some(self.duty),
+            // The sequencer is clocked every other CPU cycle, and has 8 steps
+            frequency: (volume > 0).then(|| sequence_frequency(self.period, 16)),
+        }
+    }
+
     pub fn tick(&mut self) {
         if self.timer != 0 {
             self.timer -= 1;
@@ -84,6 +99,7 @@ impl SquareWave {
     // $4000/$4004
     // DDLC VVVV
     pub fn write_control(&mut self, value: u8) {
+        self.duty = value >> 6;
         self.duty_cycle_mask = match value >> 6 {
             0 => 0b00000001, // 0 1 0 0 0 0 0 0 (12.5%)
             1 => 0b00000011, // 0 1 1 0 0 0 0 0 (25%)
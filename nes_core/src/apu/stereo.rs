@@ -0,0 +1,95 @@
+use crate::apu::AudioChannels;
+
+/// How loud a channel is, and where it sits between the left and right speakers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelMix {
+    /// -1.0 is only the left speaker, 0.0 is both, 1.0 is only the right speaker
+    pub pan: f32,
+    /// Multiplies the channel's level, 1.0 is the normal level
+    pub gain: f32,
+}
+
+impl Default for ChannelMix {
+    fn default() -> ChannelMix {
+        ChannelMix { pan: 0.0, gain: 1.0 }
+    }
+}
+
+/// The NES only has mono output, so this is a mixer for the emulator's stereo output.
+/// The channels still go through the nonlinear mixer, so they interact like they do on hardware,
+/// but with the formulas rather than the lookup tables, as the levels are no longer whole numbers.
+/// https://www.nesdev.org/wiki/APU_Mixer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StereoMix {
+    /// In the same order as `AudioChannels::EACH`
+    pub channels: [ChannelMix; 5],
+}
+
+impl StereoMix {
+    /// The classic headphone mix, with the two pulse channels on opposite sides.
+    pub fn split_pulses() -> StereoMix {
+        let mut mix = StereoMix::default();
+        mix.channels[0].pan = -0.75;
+        mix.channels[1].pan = 0.75;
+        mix
+    }
+
+    /// The mix for a single channel, or `None` for a combination of channels.
+    pub fn channel(&self, channel: AudioChannels) -> Option<&ChannelMix> {
+        channel_index(channel).map(|index| &self.channels[index])
+    }
+
+    pub fn channel_mut(&mut self, channel: AudioChannels) -> Option<&mut ChannelMix> {
+        channel_index(channel).map(|index| &mut self.channels[index])
+    }
+
+    /// Mixes the channels' output levels, in the order of `AudioChannels::EACH`, into the left and
+    /// right outputs.
+    pub(super) fn mix(&self, levels: [u8; 5]) -> (f32, f32) {
+        let mut left = [0.0f32; 5];
+        let mut right = [0.0f32; 5];
+        for (i, (level, mix)) in levels.iter().zip(self.channels.iter()).enumerate() {
+            // Panning to one side doesn't make the channel louder on the other side
+            let level = *level as f32 * mix.gain;
+            left[i] = level * (1.0 - mix.pan).min(1.0);
+            right[i] = level * (1.0 + mix.pan).min(1.0);
+        }
+        (mix_levels(&left), mix_levels(&right))
+    }
+}
+
+fn channel_index(channel: AudioChannels) -> Option<usize> {
+    AudioChannels::EACH.iter().position(|c| *c == channel)
+}
+
+fn mix_levels([pulse1, pulse2, triangle, noise, dmc]: &[f32; 5]) -> f32 {
+    let pulse = pulse1 + pulse2;
+    let pulse_out = if pulse > 0.0 { 95.88 / (8128.0 / pulse + 100.0) } else { 0.0 };
+    let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+    let tnd_out = if tnd > 0.0 { 159.79 / (1.0 / tnd + 100.0) } else { 0.0 };
+    pulse_out + tnd_out
+}
+
+#[test]
+fn test_stereo_mix() {
+    // Centred channels are the same on both sides, and close to the lookup tables' mix
+    let (left, right) = StereoMix::default().mix([15, 15, 15, 15, 127]);
+    assert_eq!(left, right);
+    assert!((left - 1.0).abs() < 0.01);
+
+    let mix = StereoMix::split_pulses();
+    let (left, right) = mix.mix([15, 0, 0, 0, 0]);
+    assert!(left > right && right > 0.0);
+    let (left, right) = mix.mix([0, 15, 0, 0, 0]);
+    assert!(right > left);
+
+    let mut mix = StereoMix::default();
+    mix.channel_mut(AudioChannels::TRIANGLE).unwrap().pan = 1.0;
+    mix.channel_mut(AudioChannels::NOISE).unwrap().gain = 0.0;
+    assert_eq!(mix.mix([0, 0, 15, 15, 0]).0, 0.0);
+
+    // Only single channels have a mix
+    assert_eq!(mix.channel(AudioChannels::SQUARE1), Some(&mix.channels[0]));
+    assert_eq!(mix.channel(AudioChannels::SQUARE1 | AudioChannels::SQUARE2), None);
+    assert_eq!(mix.channel_mut(AudioChannels::empty()), None);
+}
@@ -1,3 +1,4 @@
+use crate::apu::channel_output::{ChannelState, sequence_frequency};
 use crate::apu::length_counter::LengthCounter;
 use crate::apu::linear_counter::LinearCounter;
 
@@ -7,6 +8,10 @@ pub struct TriangleWave {
     sequence_pos: usize,
     pub length_counter: LengthCounter,
     pub linear_counter: LinearCounter,
+    /// Stop the sequencer at ultrasonic periods (less than 2), which games use to silence the
+    /// triangle. On hardware it keeps stepping, and the output averages out to a mid level that
+    /// can pop when it starts and stops, so this is an option for those who'd rather avoid that.
+    pub silence_ultrasonic: bool,
 }
 
 impl TriangleWave {
@@ -17,6 +22,7 @@ impl TriangleWave {
             sequence_pos: 0,
             length_counter: LengthCounter::new(),
             linear_counter: LinearCounter::new(),
+            silence_ultrasonic: false,
         }
     }
 
@@ -24,7 +30,13 @@ impl TriangleWave {
         if self.timer != 0 {
             self.timer -= 1;
         } else {
-            self.clock_waveform_generator();
+            // The sequencer is only clocked while both counters are non-zero, otherwise it holds
+            // its position and keeps outputting the same level.
+            let halted = self.length_counter.is_zero() || self.linear_counter.is_zero()
+                || (self.silence_ultrasonic && self.period < 2);
+            if !halted {
+                self.clock_waveform_generator();
+            }
             self.timer = self.period;
         }
     }
@@ -38,21 +50,21 @@ impl TriangleWave {
         0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
     ];
 
+    // Unlike the other channels, the triangle isn't silenced by its counters, it just stops
+    // where it is.
     pub fn get_current_output(&self) -> u8 {
-        let mut volume = Self::OUTPUT_SEQUENCE[self.sequence_pos];
+        Self::OUTPUT_SEQUENCE[self.sequence_pos]
+    }
 
-        // At the expense of accuracy, [popping] can be eliminated in an emulator e.g. by halting the triangle channel when an ultrasonic frequency is set (a timer value less than 2).
-        if self.period < 2 {
-            volume = 0;
-        }
-        if self.length_counter.is_zero() {
-            volume = 0;
+    pub fn channel_state(&self) -> ChannelState {
+        let audible = (self.period >= 2 || !self.silence_ultrasonic) && !self.length_counter.is_zero() && !self.linear_counter.is_zero();
+        ChannelState {
+            period: self.period,
+            // The triangle doesn't have a volume control, it's either playing or not
+            volume: if audible { 15 } else { 0 },
+            duty: None,
+            frequency: audible.then(|| sequence_frequency(self.period, 32)),
         }
-        if self.linear_counter.is_zero() {
-            volume = 0;
-        }
-
-        volume
     }
 
     // $4008
@@ -1,6 +1,6 @@
 use std::error::Error;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use log::{info};
 use crate::mapper::MapperDescriptor;
 
@@ -65,8 +65,24 @@ pub fn parse_rom(filename: &Path) -> Result<Cartridge, Box<dyn Error>> {
             return Err(format!("Header 8 value {} not supported", header[8]).into());
         }
     }
-    if chr_rom_size == 0 {
-        chr_ram_size = 8192;
+    if chr_rom_size == 0 && chr_ram_size == 0 {
+        chr_ram_size = if matches!(mapper_num, 28 | 30) {
+            // Action 53 and UNROM 512 boards have 32K of CHR RAM unless a NES 2.0 header says otherwise
+            32 * 1024
+        } else {
+            8192
+        };
+    }
+
+    // The trainer was for copiers, which loaded it at $7000. Nothing uses it, so it's skipped.
+    let mut prg_rom_file_offset = header.len();
+    if header[6] & 0b100 != 0 {
+        if rest.len() < 512 {
+            return Err("This NES ROM appears to be invalid (too short)".into());
+        }
+        info!("Skipping a 512 byte trainer");
+        rest = &rest[512..];
+        prg_rom_file_offset += 512;
     }
 
     prg_rom_size *= 16 * 1024;
@@ -92,7 +108,10 @@ pub fn parse_rom(filename: &Path) -> Result<Cartridge, Box<dyn Error>> {
     };
 
     if let Some(submapper_num) = submapper_num {
-        return Err(format!("Submapper field not supported. (got {submapper_num})").into());
+        if !mapper_descriptor.supports_submapper(submapper_num as u8) {
+            return Err(format!("Submapper {submapper_num} of mapper #{mapper_num} not supported").into());
+        }
+        info!("Mapper #{mapper_num}.{submapper_num}: {}", mapper_descriptor.name);
     } else {
         info!("Mapper #{mapper_num}: {}", mapper_descriptor.name);
     }
@@ -108,6 +127,12 @@ pub fn parse_rom(filename: &Path) -> Result<Cartridge, Box<dyn Error>> {
 
     let prg_ram_battery_backed = header[6] & 0b10 != 0;
 
+    // UNROM 512 uses the four-screen bit on its own for one-screen mirroring. With the vertical bit
+    // as well it's the four-screen board, which keeps its nametables in CHR RAM.
+    if mapper_num == 30 && header[6] & 0b1001 == 0b1001 {
+        return Err("UNROM 512 with four-screen mirroring isn't supported yet".into());
+    }
+
     let mirroring = if header[6] & 0b1000 != 0 {
         NametableMirroring::FourScreen
     } else if header[6] & 0x01 == 0 {
@@ -118,22 +143,50 @@ pub fn parse_rom(filename: &Path) -> Result<Cartridge, Box<dyn Error>> {
 
     Ok(Cartridge {
         mapper_descriptor,
+        submapper: submapper_num.unwrap_or(0) as u8,
         prg_ram_size,
         prg_ram_battery_backed,
         prg_rom: prg_rom.to_vec(),
+        prg_rom_file_offset,
         chr,
         mirroring,
+        save_path: Some(filename.with_extension("sav")),
     })
 }
 
 #[derive(Clone)]
 pub struct Cartridge {
     pub mapper_descriptor: MapperDescriptor,
+    /// The NES 2.0 submapper number, or 0 if the ROM only has an iNES header.
+    pub submapper: u8,
     pub prg_rom: Vec<u8>,
+    /// Where the PRG ROM starts in the ROM file, after the header and any trainer.
+    pub prg_rom_file_offset: usize,
     pub chr: CHR,
     pub prg_ram_size: u32,
     pub prg_ram_battery_backed: bool,
     pub mirroring: NametableMirroring,
+    /// Where to persist battery-backed or flash memory, if the board has any.
+    pub save_path: Option<PathBuf>,
+}
+
+impl Cartridge {
+    /// A cartridge for tests, with vertical mirroring, no PRG RAM and nowhere to save.
+    /// Override other fields with `Cartridge { submapper: 1, ..Cartridge::test_cart(..) }`.
+    #[cfg(test)]
+    pub(crate) fn test_cart(mapper_descriptor: MapperDescriptor, prg_rom: Vec<u8>, chr: CHR) -> Cartridge {
+        Cartridge {
+            mapper_descriptor,
+            submapper: 0,
+            prg_rom,
+            prg_rom_file_offset: 16,
+            chr,
+            prg_ram_size: 0,
+            prg_ram_battery_backed: false,
+            mirroring: NametableMirroring::Vertical,
+            save_path: None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -155,3 +208,34 @@ pub enum NametableMirroring {
 fn test_parse_rom() {
     parse_rom(Path::new("../samples/hello_green.nes")).unwrap();
 }
+
+#[test]
+fn test_parse_unrom512_mirroring() {
+    let parse_header = |flags6: u8| {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 2, 0, flags6 | 0xE0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.resize(16 + 2 * 0x4000, 0);
+        let path = std::env::temp_dir().join(format!("nes_core_test_unrom512_{}_{flags6}.nes", std::process::id()));
+        std::fs::write(&path, rom).unwrap();
+        let result = parse_rom(&path).map(|cart| cart.mirroring);
+        std::fs::remove_file(&path).unwrap();
+        result
+    };
+    assert!(matches!(parse_header(0b1000), Ok(NametableMirroring::FourScreen)));
+    assert!(parse_header(0b1001).is_err());
+}
+
+#[test]
+fn test_parse_trainer() {
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0b100, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.resize(16 + 512, 0xAA);
+    rom.push(0x42);
+    rom.resize(16 + 512 + 0x4000 + 0x2000, 0);
+    let path = std::env::temp_dir().join(format!("nes_core_test_trainer_{}.nes", std::process::id()));
+    std::fs::write(&path, rom).unwrap();
+    let cart = parse_rom(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // The trainer is skipped, and counted in the PRG ROM's offset in the file
+    assert_eq!(cart.prg_rom[0], 0x42);
+    assert_eq!(cart.prg_rom_file_offset, 16 + 512);
+}
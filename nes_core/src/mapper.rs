@@ -1,8 +1,10 @@
 use std::any::Any;
 use std::cell::{Cell, RefCell};
+use std::io;
 use std::rc::Rc;
 use crate::cartridge::Cartridge;
 use crate::mapper::memory_map::MemoryMap;
+pub use crate::mapper::memory_map::{CiramPage, NametableFillFn, NametableSource};
 use crate::nes::Signals;
 
 mod nrom;
@@ -14,11 +16,16 @@ mod mmc3;
 mod memory_map;
 mod axrom;
 mod dxrom;
+mod bus_conflicts;
+mod unrom512;
+mod action53;
 
 const DEBUG_MAPPINGS: bool = false;
 
 /// The mapper covers two address spaces - the CPU memory map, and the PPU memory map.
 /// The CPU memory map is 16-bit, and the PPU memory map is 14-bit.
+/// `write_main_bus` sees writes to $4020-$5FFF and $8000-$FFFF, mappers must ignore the addresses
+/// they don't decode.
 ///
 /// There's only one method for each address space, and the `write` parameter tells us whether we're
 /// reading or writing (so we don't have to duplicate the address logic between reads and writes).
@@ -30,17 +37,76 @@ pub trait RawMapper : Any {
     /// Returns a callback to be invoked after reading the PPU pattern table.
     fn get_ppu_pattern_post_read_hook(&self) -> Option<Rc<PPUPatternPostReadHook>> { None }
 
+    /// Returns a callback that can replace the value read from PRG ROM, for boards where PRG isn't
+    /// plain ROM (e.g. a flash chip in its ID mode).
+    fn get_prg_read_override(&self) -> Option<Rc<PRGReadOverride>> { None }
+
     fn on_cycle_scanline(&mut self) {}
+
+    /// Called when the console's reset button is pressed. Most boards aren't connected to the reset
+    /// line and keep all their registers, which is the default.
+    fn on_soft_reset(&mut self, _memory: &mut MemoryMap) {}
+
+    /// Write any battery-backed or flash memory out to disk.
+    fn save_persistent_data(&mut self, _memory: &MemoryMap) -> io::Result<()> { Ok(()) }
+
+    /// The values the mapper has latched, for debuggers.
+    fn describe_registers(&self) -> Vec<MapperRegister> { Vec::new() }
+}
+
+/// One of a mapper's internal registers, as shown in a debugger.
+#[derive(Clone, Debug)]
+pub struct MapperRegister {
+    pub name: &'static str,
+    pub value: u32,
+    /// A readable interpretation of the value, e.g. the banking mode it selects.
+    pub description: Option<String>,
+}
+
+impl MapperRegister {
+    pub fn new(name: &'static str, value: impl Into<u32>) -> MapperRegister {
+        MapperRegister { name, value: value.into(), description: None }
+    }
+
+    pub fn with_description(name: &'static str, value: impl Into<u32>, description: impl Into<String>) -> MapperRegister {
+        MapperRegister { name, value: value.into(), description: Some(description.into()) }
+    }
+}
+
+/// Where each slot of the CPU and PPU address spaces is currently mapped to.
+#[derive(Clone, Debug)]
+pub struct BankLayout {
+    /// PRG ROM offsets of the 8K slots at $8000, $A000, $C000 and $E000.
+    pub prg_rom_offsets: [usize; 4],
+    /// CHR ROM/RAM offsets of the 1K slots from $0000 to $1FFF.
+    pub chr_offsets: [usize; 8],
+    pub chr_is_ram: bool,
+    /// What the nametables at $2000, $2400, $2800 and $2C00 are mapped to.
+    pub nametables: [NametableSource; 4],
+}
+
+/// A snapshot of a mapper's state, for debuggers.
+#[derive(Clone, Debug)]
+pub struct MapperDebugState {
+    pub name: &'static str,
+    pub registers: Vec<MapperRegister>,
+    pub layout: BankLayout,
 }
 
 /// A callback to invoke after reading the PPU pattern table.
 pub type PPUPatternPostReadHook = dyn Fn(&mut MemoryMap, u16);
 
+/// A callback to invoke instead of reading PRG ROM. Returns None to read PRG ROM as usual.
+pub type PRGReadOverride = dyn Fn(&MemoryMap, u16) -> Option<u8>;
+
+/// Creates a mapper for the given cartridge.
+pub type NewMapperFn = fn(&Cartridge, Rc<Signals>) -> Box<RefCell<dyn RawMapper>>;
+
 #[derive(Copy, Clone)]
 pub struct MapperDescriptor {
     pub number: u32,
     pub name: &'static str,
-    pub new_mapper: fn(Rc<Signals>) -> Box<RefCell<dyn RawMapper>>,
+    pub new_mapper: NewMapperFn,
 }
 
 static DESCRIPTORS: &[MapperDescriptor] = &[
@@ -50,7 +116,9 @@ static DESCRIPTORS: &[MapperDescriptor] = &[
     MapperDescriptor::CNROM,
     MapperDescriptor::MMC3,
     MapperDescriptor::AxROM,
+    MapperDescriptor::Action53,
     MapperDescriptor::MMC2,
+    MapperDescriptor::UNROM512,
     MapperDescriptor::DxROM,
 ];
 
@@ -69,79 +137,119 @@ impl MapperDescriptor {
         None
     }
 
+    /// Whether the NES 2.0 submapper is one this mapper's implementation understands. Every mapper
+    /// supports 0, the default.
+    pub fn supports_submapper(&self, submapper: u8) -> bool {
+        match self.number {
+            // UxROM, CNROM and AxROM: the bus conflict behaviour, see `BusConflicts::for_submapper`
+            2 | 3 | 7 => submapper <= 2,
+            _ => submapper == 0,
+        }
+    }
+
     pub const NROM: MapperDescriptor = MapperDescriptor {
         number: 0,
         name: "NROM",
-        new_mapper: |_| wrap(nrom::NRomMapper::new()),
+        new_mapper: |_, _| wrap(nrom::NRomMapper::new()),
     };
     pub const MMC1: MapperDescriptor = MapperDescriptor {
         number: 1,
         name: "MMC1",
-        new_mapper: |_| wrap(mmc1::MMC1Mapper::new()),
+        new_mapper: |_, _| wrap(mmc1::MMC1Mapper::new()),
     };
     pub const UxROM: MapperDescriptor = MapperDescriptor {
         number: 2,
         name: "UxROM",
-        new_mapper: |_| wrap(uxrom::UxRomMapper::new()),
+        new_mapper: |cart, _| wrap(uxrom::UxRomMapper::new(cart)),
     };
     pub const CNROM: MapperDescriptor = MapperDescriptor {
         number: 3,
         name: "CNROM",
-        new_mapper: |_| wrap(cnrom::CNRomMapper::new()),
+        new_mapper: |cart, _| wrap(cnrom::CNRomMapper::new(cart)),
     };
     pub const MMC3: MapperDescriptor = MapperDescriptor {
         number: 4,
         name: "MMC3",
-        new_mapper: |signals| wrap(mmc3::MMC3Mapper::new(signals)),
+        new_mapper: |_, signals| wrap(mmc3::MMC3Mapper::new(signals)),
     };
     pub const AxROM: MapperDescriptor = MapperDescriptor {
         number: 7,
         name: "AxROM",
-        new_mapper: |_| wrap(axrom::AxRomMapper::new()),
+        new_mapper: |cart, _| wrap(axrom::AxRomMapper::new(cart)),
     };
     pub const MMC2: MapperDescriptor = MapperDescriptor {
         number: 9,
         name: "MMC2",
-        new_mapper: |_| wrap(mmc2::MMC2Mapper::new()),
+        new_mapper: |_, _| wrap(mmc2::MMC2Mapper::new()),
+    };
+    pub const Action53: MapperDescriptor = MapperDescriptor {
+        number: 28,
+        name: "Action 53",
+        new_mapper: |_, _| wrap(action53::Action53Mapper::new()),
+    };
+    pub const UNROM512: MapperDescriptor = MapperDescriptor {
+        number: 30,
+        name: "UNROM 512",
+        new_mapper: |cart, _| wrap(unrom512::UnRom512Mapper::new(cart)),
     };
     pub const DxROM: MapperDescriptor = MapperDescriptor {
         number: 206,
         name: "DxROM/Tengen MIMIC-1/Namcot 118",
-        new_mapper: |_| wrap(dxrom::DxROMMapper::new()),
+        new_mapper: |_, _| wrap(dxrom::DxROMMapper::new()),
     };
 }
 
 pub struct Mapper {
+    name: &'static str,
     raw_mapper: Box<RefCell<dyn RawMapper>>,
     memory_map: RefCell<MemoryMap>,
     ppu_pattern_post_read_hook: Option<Rc<PPUPatternPostReadHook>>,
+    prg_read_override: Option<Rc<PRGReadOverride>>,
+    prg_rom_file_offset: usize,
     /// 0x6000-0x7FFF
     wram: Box<[Cell<u8>; 0x2000]>,
+    /// Every address the PPU has read, for tests of when it fetches what
+    #[cfg(test)]
+    ppu_reads: RefCell<Vec<u16>>,
 }
 
 impl Mapper {
     pub fn new(cart: Cartridge, signals: Rc<Signals>) -> Mapper {
-        let raw_mapper: Box<RefCell<dyn RawMapper>> = (cart.mapper_descriptor.new_mapper)(signals);
+        let raw_mapper: Box<RefCell<dyn RawMapper>> = (cart.mapper_descriptor.new_mapper)(&cart, signals);
 
+        let name = cart.mapper_descriptor.name;
+        let prg_rom_file_offset = cart.prg_rom_file_offset;
         let memory_map = RefCell::new(MemoryMap::new(cart));
 
         raw_mapper.borrow_mut().init_memory_map(&mut memory_map.borrow_mut());
 
         let ppu_pattern_post_read_hook: Option<Rc<PPUPatternPostReadHook>> = raw_mapper.borrow_mut().get_ppu_pattern_post_read_hook();
+        let prg_read_override: Option<Rc<PRGReadOverride>> = raw_mapper.borrow().get_prg_read_override();
 
         const U8_0: Cell<u8> = Cell::new(0);
         Mapper {
+            name,
             raw_mapper,
             memory_map,
             ppu_pattern_post_read_hook,
+            prg_read_override,
+            prg_rom_file_offset,
             wram: Box::new([U8_0; 0x2000]),
+            #[cfg(test)]
+            ppu_reads: RefCell::new(Vec::new()),
         }
     }
 
     pub fn read_main_bus(&self, addr: u16) -> u8 {
         match addr {
             0x8000..=0xFFFF => {
-                self.memory_map.borrow().read_prg(addr)
+                let memory_map = self.memory_map.borrow();
+                if let Some(prg_read_override) = self.prg_read_override.as_ref() {
+                    if let Some(value) = prg_read_override(&memory_map, addr) {
+                        return value;
+                    }
+                }
+                memory_map.read_prg(addr)
             }
             0x6000..=0x7FFF => {
                 self.wram[addr as usize & 0x1FFF].get()
@@ -154,7 +262,7 @@ impl Mapper {
 
     pub fn write_main_bus(&self, addr: u16, value: u8) {
         match addr {
-            0x8000..=0xFFFF => {
+            0x4020..=0x5FFF | 0x8000..=0xFFFF => {
                 self.raw_mapper.borrow_mut().write_main_bus(&mut self.memory_map.borrow_mut(), addr, value);
             }
             0x6000..=0x7FFF => {
@@ -168,11 +276,15 @@ impl Mapper {
 
     #[inline(always)]
     pub fn read_nametable(&self, addr: u16) -> u8 {
+        #[cfg(test)]
+        self.ppu_reads.borrow_mut().push(addr);
         self.memory_map.borrow().read_nametable(addr)
     }
 
     #[inline(always)]
     pub fn read_pattern_table(&self, addr: u16) -> u8 {
+        #[cfg(test)]
+        self.ppu_reads.borrow_mut().push(addr);
         let result = self.memory_map.borrow().read_pattern_table(addr);
         if let Some(post_read_hook) = self.ppu_pattern_post_read_hook.as_ref() {
             post_read_hook(&mut self.memory_map.borrow_mut(), addr);
@@ -180,6 +292,12 @@ impl Mapper {
         result
     }
 
+    /// The addresses the PPU has read since this was last called.
+    #[cfg(test)]
+    pub(crate) fn take_ppu_reads(&self) -> Vec<u16> {
+        self.ppu_reads.take()
+    }
+
     pub fn write_nametable(&self, addr: u16, value: u8) {
         self.memory_map.borrow_mut().write_nametable(addr, value);
     }
@@ -191,6 +309,48 @@ impl Mapper {
     pub fn on_cycle_scanline(&self) {
         self.raw_mapper.borrow_mut().on_cycle_scanline();
     }
+
+    pub fn on_soft_reset(&self) {
+        self.raw_mapper.borrow_mut().on_soft_reset(&mut self.memory_map.borrow_mut());
+    }
+
+    pub fn debug_state(&self) -> MapperDebugState {
+        MapperDebugState {
+            name: self.name,
+            registers: self.raw_mapper.borrow().describe_registers(),
+            layout: self.memory_map.borrow().bank_layout(),
+        }
+    }
+
+    /// The offset into the PRG ROM that a CPU address currently reads from, or None if the address
+    /// isn't mapped to PRG ROM.
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.memory_map.borrow().prg_rom_offset(addr)),
+            _ => None,
+        }
+    }
+
+    /// Where in the ROM file a CPU address currently reads from, or None if the address isn't
+    /// mapped to PRG ROM. This is `prg_rom_offset` plus the header and any trainer before the PRG ROM.
+    pub fn prg_rom_file_offset(&self, addr: u16) -> Option<usize> {
+        self.prg_rom_offset(addr).map(|offset| self.prg_rom_file_offset + offset)
+    }
+
+    /// Write any battery-backed or flash memory out to disk, if it's changed since it was last saved.
+    /// Hosts should call this before unloading the ROM or exiting, so they can report any errors.
+    pub fn save_persistent_data(&self) -> io::Result<()> {
+        self.raw_mapper.borrow_mut().save_persistent_data(&self.memory_map.borrow())
+    }
+}
+
+/// A last resort for hosts that don't call `save_persistent_data`, which can't report errors.
+impl Drop for Mapper {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_persistent_data() {
+            log::warn!("Failed to save persistent data: {e}");
+        }
+    }
 }
 
 #[inline(never)]
@@ -212,3 +372,41 @@ pub fn out_of_bounds_write(context: &str, addr: u16, value: u8) {
         log::warn!("Attempted to write {context} out of bounds at {addr:04X} with {value} (0x{value:02X})");
     }
 }
+
+#[test]
+fn test_debug_state() {
+    use crate::cartridge::CHR;
+
+    let cart = Cartridge {
+        submapper: 1, // No bus conflicts
+        ..Cartridge::test_cart(MapperDescriptor::UxROM, vec![0; 8 * 0x4000], CHR::RAM(0x2000))
+    };
+    let mapper = Mapper::new(cart, Signals::new());
+    mapper.write_main_bus(0x8000, 3);
+
+    assert_eq!(mapper.prg_rom_offset(0x8123), Some(3 * 0x4000 + 0x123));
+    assert_eq!(mapper.prg_rom_offset(0xFFFC), Some(8 * 0x4000 - 4));
+    assert_eq!(mapper.prg_rom_offset(0x6000), None);
+    assert_eq!(mapper.prg_rom_file_offset(0x8123), Some(16 + 3 * 0x4000 + 0x123));
+    assert_eq!(mapper.prg_rom_file_offset(0x6000), None);
+
+    let state = mapper.debug_state();
+    assert_eq!(state.name, "UxROM");
+    assert_eq!(state.registers[0].value, 3);
+    assert_eq!(state.layout.prg_rom_offsets, [0xC000, 0xE000, 0x1C000, 0x1E000]);
+    assert!(matches!(state.layout.nametables, [
+        NametableSource::Ciram(CiramPage::Page0),
+        NametableSource::Ciram(CiramPage::Page1),
+        NametableSource::Ciram(CiramPage::Page0),
+        NametableSource::Ciram(CiramPage::Page1),
+    ]));
+}
+
+#[test]
+fn test_supports_submapper() {
+    assert!(MapperDescriptor::UxROM.supports_submapper(2));
+    assert!(!MapperDescriptor::UxROM.supports_submapper(3));
+    assert!(MapperDescriptor::MMC1.supports_submapper(0));
+    assert!(!MapperDescriptor::MMC1.supports_submapper(5));
+    assert!(!MapperDescriptor::UNROM512.supports_submapper(1));
+}
@@ -0,0 +1,178 @@
+use log::{info, trace};
+use crate::cartridge::NametableMirroring;
+use crate::mapper;
+use crate::mapper::{MapperRegister, RawMapper};
+use crate::mapper::memory_map::MemoryMap;
+
+/// Mapper 28: Action 53
+/// https://www.nesdev.org/wiki/Action_53
+///
+/// A multicart mapper that can emulate NROM, CNROM-style CHR RAM switching, UxROM, AxROM and
+/// BxROM-style games inside an "outer bank" of up to 256K, which the menu uses to select the game.
+pub struct Action53Mapper {
+    /// Selected by writes to $5000-$5FFF, the next write to $8000-$FFFF goes to this register.
+    reg_select: RegSelect,
+    /// Register $00: the 8K CHR RAM bank
+    chr_bank: u8,
+    /// Register $01: the PRG bank within the current game
+    inner_bank: u8,
+    /// Register $80: mirroring, PRG bank mode and game size
+    mode: u8,
+    /// Register $81: the 32K bank of the cart that the current game occupies
+    outer_bank: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RegSelect {
+    ChrBank = 0x00,
+    InnerBank = 0x01,
+    Mode = 0x80,
+    OuterBank = 0x81,
+}
+
+impl Action53Mapper {
+    pub fn new() -> Action53Mapper {
+        Action53Mapper {
+            reg_select: RegSelect::ChrBank,
+            chr_bank: 0,
+            inner_bank: 0,
+            // 32K banks in a 32K game, so the last 32K (with the menu's reset vector) is mapped.
+            mode: 0,
+            outer_bank: 0xFF,
+        }
+    }
+
+    /// The 16K PRG bank mapped at $8000 (a14 = 0) or $C000 (a14 = 1).
+    fn prg_bank_16k(&self, a14: u8) -> i32 {
+        let bank_mode = self.mode >> 2 & 0b11;
+        let game_size = self.mode >> 4 & 0b11;
+        let outer_bank = (self.outer_bank as u32) << 1;
+
+        let inner_bank: u32 = match bank_mode {
+            // 32K banks
+            0 | 1 => (self.inner_bank as u32) << 1 | a14 as u32,
+            // UNROM-style with the first bank of the outer bank fixed at $8000, or the last fixed at $C000
+            2 if a14 == 0 => return outer_bank as i32,
+            3 if a14 == 1 => return (outer_bank | 1) as i32,
+            _ => self.inner_bank as u32,
+        };
+
+        // The game size decides how many of the low bank bits come from the inner bank.
+        let inner_mask: u32 = (2 << game_size) - 1;
+        ((outer_bank & !inner_mask) | (inner_bank & inner_mask)) as i32
+    }
+
+    fn sync_mappings(&self, memory: &mut MemoryMap) {
+        memory.map_prg_16k(0, self.prg_bank_16k(0));
+        memory.map_prg_16k(1, self.prg_bank_16k(1));
+
+        let chr_banks = (memory.chr_len() / 0x2000).max(1);
+        memory.map_chr_8k((self.chr_bank as usize % chr_banks) * 0x2000);
+
+        memory.set_nametable_mirroring(match self.mode & 0b11 {
+            0 => NametableMirroring::SingleScreenLowerBank,
+            1 => NametableMirroring::SingleScreenUpperBank,
+            2 => NametableMirroring::Vertical,
+            3 => NametableMirroring::Horizontal,
+            _ => unreachable!(),
+        });
+    }
+
+    fn write_register(&mut self, memory: &mut MemoryMap, value: u8) {
+        match self.reg_select {
+            RegSelect::ChrBank | RegSelect::InnerBank => {
+                if let RegSelect::ChrBank = self.reg_select {
+                    self.chr_bank = value & 0b11;
+                } else {
+                    self.inner_bank = value & 0b1111;
+                }
+                // In the one-screen mirroring modes, bit 4 also selects the screen, so AxROM games work.
+                if self.mode & 0b10 == 0 {
+                    self.mode = (self.mode & !1) | (value >> 4 & 1);
+                }
+            }
+            RegSelect::Mode => {
+                self.mode = value & 0b11_1111;
+            }
+            RegSelect::OuterBank => {
+                self.outer_bank = value;
+            }
+        }
+        trace!("Action 53 {:?} = {value:02X}", self.reg_select);
+        self.sync_mappings(memory);
+    }
+}
+
+impl RawMapper for Action53Mapper {
+    fn init_memory_map(&self, memory: &mut MemoryMap) {
+        self.sync_mappings(memory);
+    }
+
+    fn write_main_bus(&mut self, memory: &mut MemoryMap, addr: u16, value: u8) {
+        match addr {
+            0x5000..=0x5FFF => {
+                self.reg_select = match value & 0x81 {
+                    0x00 => RegSelect::ChrBank,
+                    0x01 => RegSelect::InnerBank,
+                    0x80 => RegSelect::Mode,
+                    0x81 => RegSelect::OuterBank,
+                    _ => unreachable!(),
+                };
+            }
+            0x8000..=0xFFFF => {
+                self.write_register(memory, value);
+            }
+            _ => mapper::out_of_bounds_write("CPU memory map", addr, value),
+        }
+    }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        vec![
+            MapperRegister::with_description("Register select", self.reg_select as u8, format!("{:?}", self.reg_select)),
+            MapperRegister::new("CHR bank ($00)", self.chr_bank),
+            MapperRegister::new("Inner bank ($01)", self.inner_bank),
+            MapperRegister::new("Mode ($80)", self.mode),
+            MapperRegister::new("Outer bank ($81)", self.outer_bank),
+        ]
+    }
+
+    fn on_soft_reset(&mut self, _memory: &mut MemoryMap) {
+        // The board has no reset line, so reset keeps the current game's outer bank mapped. Each
+        // game's reset vector is patched by the compilation builder to return to the menu, which
+        // then tells a soft reset apart from power-on by checking RAM.
+        info!("Soft reset, keeping outer bank {:02X}", self.outer_bank);
+    }
+}
+
+#[test]
+fn test_prg_banks() {
+    let mut mapper = Action53Mapper::new();
+
+    // Power on maps the last 32K bank
+    assert_eq!(mapper.prg_bank_16k(0), 0x1FE);
+    assert_eq!(mapper.prg_bank_16k(1), 0x1FF);
+
+    // A 128K UNROM game in the second 128K of the cart
+    mapper.outer_bank = 0x07;
+    mapper.mode = 0b10_11_00;
+    mapper.inner_bank = 2;
+    assert_eq!(mapper.prg_bank_16k(0), 0x0A);
+    assert_eq!(mapper.prg_bank_16k(1), 0x0F);
+
+    // An UNROM game with the first bank fixed at $8000
+    mapper.mode = 0b10_10_00;
+    assert_eq!(mapper.prg_bank_16k(0), 0x0E);
+    assert_eq!(mapper.prg_bank_16k(1), 0x0A);
+
+    // A 64K game with 32K banks (BNROM/AOROM style)
+    mapper.outer_bank = 0x03;
+    mapper.mode = 0b01_00_00;
+    mapper.inner_bank = 1;
+    assert_eq!(mapper.prg_bank_16k(0), 0x06);
+    assert_eq!(mapper.prg_bank_16k(1), 0x07);
+
+    // A 32K NROM game ignores the inner bank
+    mapper.mode = 0b00_00_00;
+    assert_eq!(mapper.prg_bank_16k(0), 0x06);
+    assert_eq!(mapper.prg_bank_16k(1), 0x07);
+}
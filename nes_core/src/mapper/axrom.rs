@@ -1,16 +1,22 @@
-use crate::cartridge::NametableMirroring;
+use crate::cartridge::{Cartridge, NametableMirroring};
+use crate::mapper::bus_conflicts::{BusConflictResolver, BusConflicts};
 use crate::mapper::memory_map::MemoryMap;
-use crate::mapper::RawMapper;
+use crate::mapper;
+use crate::mapper::{MapperRegister, RawMapper};
 
 pub struct AxRomMapper {
+    bus_conflicts: BusConflictResolver,
     prg_bank: u8,
     // Either SingleScreenLowerBank or SingleScreenUpperBank
     mirroring: NametableMirroring,
 }
 
 impl AxRomMapper {
-    pub fn new() -> AxRomMapper {
+    pub fn new(cart: &Cartridge) -> AxRomMapper {
         AxRomMapper {
+            // Only AMROM has bus conflicts, ANROM doesn't and AOROM depends on the board revision.
+            // Games written for ANROM rely on this, so don't emulate conflicts unless asked to.
+            bus_conflicts: BusConflictResolver::new(BusConflicts::for_submapper(cart.submapper, BusConflicts::None)),
             prg_bank: 0,
             mirroring: NametableMirroring::SingleScreenLowerBank,
         }
@@ -28,7 +34,12 @@ impl RawMapper for AxRomMapper {
         self.sync_mapping(memory);
     }
 
-    fn write_main_bus(&mut self, memory: &mut MemoryMap, _addr: u16, value: u8) {
+    fn write_main_bus(&mut self, memory: &mut MemoryMap, addr: u16, value: u8) {
+        if addr < 0x8000 {
+            mapper::out_of_bounds_write("CPU memory map", addr, value);
+            return;
+        }
+        let value = self.bus_conflicts.resolve(memory, addr, value);
         self.prg_bank = value & 0b111;
         self.mirroring = match value >> 4 & 1 {
             0 => NametableMirroring::SingleScreenLowerBank,
@@ -37,4 +48,12 @@ impl RawMapper for AxRomMapper {
         };
         self.sync_mapping(memory);
     }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        let upper_bank = matches!(self.mirroring, NametableMirroring::SingleScreenUpperBank);
+        vec![
+            MapperRegister::new("PRG bank", self.prg_bank),
+            MapperRegister::with_description("Nametable", upper_bank, format!("{:?}", self.mirroring)),
+        ]
+    }
 }
@@ -0,0 +1,104 @@
+use log::{debug, warn};
+use crate::mapper::memory_map::MemoryMap;
+
+/// Discrete-logic boards (UxROM, CNROM, AxROM...) don't disable the PRG ROM's outputs when the CPU
+/// writes to their bank register, so the ROM and the CPU both drive the data bus at once. The
+/// value the register latches is the written value ANDed with the ROM byte at that address.
+/// Games avoid this by writing to an address that already holds the value being written.
+/// https://www.nesdev.org/wiki/Bus_conflict
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BusConflicts {
+    /// The board has circuitry preventing conflicts, the written value is latched verbatim.
+    None,
+    /// The written value is ANDed with the ROM byte at the written address.
+    And,
+}
+
+impl BusConflicts {
+    /// The NES 2.0 submapper for mappers 2, 3 and 7 selects the bus conflict behaviour:
+    /// 0 is unspecified (use the board's usual behaviour), 1 is no conflicts, 2 is AND conflicts.
+    /// https://www.nesdev.org/wiki/NES_2.0_submappers#002,_003,_007:_UxROM,_CNROM,_AxROM
+    pub fn for_submapper(submapper: u8, default: BusConflicts) -> BusConflicts {
+        match submapper {
+            1 => BusConflicts::None,
+            2 => BusConflicts::And,
+            _ => default,
+        }
+    }
+}
+
+/// Resolves the value a discrete-logic register sees when `value` is written to `addr`.
+pub struct BusConflictResolver {
+    conflicts: BusConflicts,
+    /// Only warn about the first conflicting write, some games do this every frame.
+    has_warned: bool,
+}
+
+impl BusConflictResolver {
+    pub fn new(conflicts: BusConflicts) -> BusConflictResolver {
+        BusConflictResolver {
+            conflicts,
+            has_warned: false,
+        }
+    }
+
+    pub fn resolve(&mut self, memory: &MemoryMap, addr: u16, value: u8) -> u8 {
+        let rom_value = memory.read_prg(addr);
+        let conflicted_value = value & rom_value;
+
+        if conflicted_value != value {
+            // Whatever we emulate, this write wouldn't behave the same on every real board.
+            if !self.has_warned {
+                self.has_warned = true;
+                warn!("Bus conflict: wrote {value:02X} to {addr:04X} but the ROM holds {rom_value:02X}, a real board with bus conflicts latches {conflicted_value:02X}");
+            } else {
+                debug!("Bus conflict: wrote {value:02X} to {addr:04X} but the ROM holds {rom_value:02X}");
+            }
+        }
+
+        match self.conflicts {
+            BusConflicts::None => value,
+            BusConflicts::And => conflicted_value,
+        }
+    }
+}
+
+#[test]
+fn test_for_submapper() {
+    assert_eq!(BusConflicts::for_submapper(0, BusConflicts::And), BusConflicts::And);
+    assert_eq!(BusConflicts::for_submapper(0, BusConflicts::None), BusConflicts::None);
+    assert_eq!(BusConflicts::for_submapper(1, BusConflicts::And), BusConflicts::None);
+    assert_eq!(BusConflicts::for_submapper(2, BusConflicts::None), BusConflicts::And);
+}
+
+#[test]
+fn test_conflicting_writes() {
+    use crate::cartridge::{Cartridge, CHR};
+    use crate::mapper::{Mapper, MapperDescriptor};
+    use crate::nes::Signals;
+
+    let new_mapper = |mapper_descriptor: MapperDescriptor, submapper: u8, prg_rom: Vec<u8>, chr: CHR| {
+        Mapper::new(Cartridge { submapper, ..Cartridge::test_cart(mapper_descriptor, prg_rom, chr) }, Signals::new())
+    };
+
+    // UxROM: the ROM holds $05 everywhere, so writing bank 7 selects bank 5
+    for (submapper, bank) in [(2, 5), (1, 7)] {
+        let mapper = new_mapper(MapperDescriptor::UxROM, submapper, vec![0x05; 8 * 0x4000], CHR::RAM(0x2000));
+        mapper.write_main_bus(0x8000, 7);
+        assert_eq!(mapper.prg_rom_offset(0x8000), Some(bank * 0x4000));
+    }
+
+    // CNROM: writing CHR bank 3 over $01 selects bank 1
+    for (submapper, bank) in [(2, 1), (1, 3)] {
+        let mapper = new_mapper(MapperDescriptor::CNROM, submapper, vec![0x01; 0x8000], CHR::ROM(vec![0; 4 * 0x2000].into_boxed_slice()));
+        mapper.write_main_bus(0x8000, 3);
+        assert_eq!(mapper.debug_state().layout.chr_offsets[0], bank * 0x2000);
+    }
+
+    // AxROM: writing PRG bank 7 over $13 selects bank 3
+    for (submapper, bank) in [(2, 3), (1, 7)] {
+        let mapper = new_mapper(MapperDescriptor::AxROM, submapper, vec![0x13; 8 * 0x8000], CHR::RAM(0x2000));
+        mapper.write_main_bus(0x8000, 0x17);
+        assert_eq!(mapper.prg_rom_offset(0x8000), Some(bank * 0x8000));
+    }
+}
@@ -1,14 +1,22 @@
-use crate::mapper::{RawMapper};
+use crate::cartridge::Cartridge;
+use crate::mapper;
+use crate::mapper::{MapperRegister, RawMapper};
+use crate::mapper::bus_conflicts::{BusConflictResolver, BusConflicts};
 use crate::mapper::memory_map::MemoryMap;
 
 /// Mapper 3: CNROM
 /// https://www.nesdev.org/wiki/INES_Mapper_003
 pub struct CNRomMapper {
+    chr_bank: u8,
+    bus_conflicts: BusConflictResolver,
 }
 
 impl CNRomMapper {
-    pub fn new() -> CNRomMapper {
+    pub fn new(cart: &Cartridge) -> CNRomMapper {
         CNRomMapper {
+            chr_bank: 0,
+            // Most CNROM boards have bus conflicts.
+            bus_conflicts: BusConflictResolver::new(BusConflicts::for_submapper(cart.submapper, BusConflicts::And)),
         }
     }
 }
@@ -29,7 +37,17 @@ impl RawMapper for CNRomMapper {
         }
     }
 
-    fn write_main_bus(&mut self, map: &mut MemoryMap, _addr: u16, value: u8) {
+    fn write_main_bus(&mut self, map: &mut MemoryMap, addr: u16, value: u8) {
+        if addr < 0x8000 {
+            mapper::out_of_bounds_write("CPU memory map", addr, value);
+            return;
+        }
+        let value = self.bus_conflicts.resolve(map, addr, value);
+        self.chr_bank = value;
         map.map_chr_8k(value as usize * 8192);
     }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        vec![MapperRegister::new("CHR bank", self.chr_bank)]
+    }
 }
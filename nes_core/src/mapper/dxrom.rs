@@ -1,5 +1,5 @@
 use crate::mapper;
-use crate::mapper::{RawMapper};
+use crate::mapper::{MapperRegister, RawMapper};
 use crate::mapper::memory_map::MemoryMap;
 
 /// https://www.nesdev.org/wiki/INES_Mapper_206
@@ -80,4 +80,14 @@ impl RawMapper for DxROMMapper {
             }
         }
     }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        const BANK_REG_NAMES: [&str; 8] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7"];
+        let mut registers: Vec<MapperRegister> = BANK_REG_NAMES.iter()
+            .zip(self.bank_reg)
+            .map(|(name, value)| MapperRegister::new(name, value))
+            .collect();
+        registers.push(MapperRegister::new("Bank select", self.bank_reg_select));
+        registers
+    }
 }
@@ -1,7 +1,10 @@
+use std::fmt::{Debug, Formatter};
 use std::ops::Range;
+use std::rc::Rc;
 use log::{warn};
 use crate::cartridge::{Cartridge, CHR, NametableMirroring};
 use crate::mapper;
+use crate::mapper::BankLayout;
 
 pub struct MemoryMap {
     /// Covers 8 x 1K banks (0x400) between 0x0000 and 0x1FFF.
@@ -15,17 +18,49 @@ pub struct MemoryMap {
     prg_base_addrs: [usize; 4],
     prg_rom: Box<[u8]>,
 
-    nametable_storage: [u8; 0x1000],
-    nametable_base_addrs: [NtOffset; 4],
+    /// The console's 2K of internal VRAM, plus 2K more for four-screen carts.
+    ciram: [u8; 0x1000],
+    /// Extra RAM on the cart that can be mapped as nametables (e.g. MMC5 ExRAM).
+    cart_nametable_ram: Box<[u8]>,
+    /// What each of the four nametables between 0x2000 and 0x2FFF reads from.
+    nametable_sources: [NametableSource; 4],
 }
 
-// This is an enum so the compiler can omit the bounds check when accessing `nametable_storage`.
-#[derive(Clone, Copy)]
-enum NtOffset {
-    Addr000 = 0x000,
-    Addr400 = 0x400,
-    Addr800 = 0x800,
-    AddrC00 = 0xC00,
+/// A 1K page of CIRAM. Only the first two exist in the console, the others need a four-screen cart.
+// This is an enum so the compiler can omit the bounds check when accessing `ciram`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CiramPage {
+    Page0 = 0x000,
+    Page1 = 0x400,
+    Page2 = 0x800,
+    Page3 = 0xC00,
+}
+
+/// A callback providing every byte of a nametable, given its PPU address (0x2000-0x2FFF).
+pub type NametableFillFn = dyn Fn(u16) -> u8;
+
+/// Where one of the four 1K nametables is mapped from.
+/// https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
+#[derive(Clone)]
+pub enum NametableSource {
+    Ciram(CiramPage),
+    /// Offset into the cart's nametable RAM, a multiple of 0x400.
+    CartRam(usize),
+    /// Offset into CHR ROM/RAM, a multiple of 0x400. Read-only if CHR is ROM (e.g. Sunsoft-4).
+    Chr(usize),
+    /// Every read is answered by the callback, writes are ignored (e.g. MMC5 fill mode).
+    Fill(Rc<NametableFillFn>),
+}
+
+impl Debug for NametableSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NametableSource::Ciram(page) => write!(f, "Ciram({page:?})"),
+            NametableSource::CartRam(offset) => write!(f, "CartRam({offset:#X})"),
+            NametableSource::Chr(offset) => write!(f, "Chr({offset:#X})"),
+            NametableSource::Fill(_) => write!(f, "Fill"),
+        }
+    }
 }
 
 const PRG_PAGE: usize = 8 * 1024;
@@ -37,7 +72,8 @@ impl MemoryMap {
             warn!("Battery-backed PRG RAM is not supported.");
         }
 
-        use self::NtOffset::Addr000;
+        use self::NametableSource::Ciram;
+        use self::CiramPage::Page0;
         let mut map = MemoryMap {
             chr_base_addrs: [0; 8],
             chr_writeable: matches!(cart.chr, CHR::RAM(_)),
@@ -49,8 +85,9 @@ impl MemoryMap {
             prg_base_addrs: [0; 4],
             prg_rom: cart.prg_rom.into_boxed_slice(),
 
-            nametable_storage: [0; 0x1000],
-            nametable_base_addrs: [Addr000, Addr000, Addr000, Addr000],
+            ciram: [0; 0x1000],
+            cart_nametable_ram: Box::new([]),
+            nametable_sources: [Ciram(Page0), Ciram(Page0), Ciram(Page0), Ciram(Page0)],
         };
         map.set_nametable_mirroring(cart.mirroring);
         map
@@ -58,27 +95,57 @@ impl MemoryMap {
 
     pub fn prg_rom_len(&self) -> usize { self.prg_rom.len() }
 
+    pub fn chr_len(&self) -> usize { self.chr_storage.len() }
+
+    pub fn prg_rom(&self) -> &[u8] { &self.prg_rom }
+
+    /// For boards with flash memory that the game can reprogram.
+    pub fn prg_rom_mut(&mut self) -> &mut [u8] { &mut self.prg_rom }
+
+    pub fn bank_layout(&self) -> BankLayout {
+        BankLayout {
+            prg_rom_offsets: self.prg_base_addrs,
+            chr_offsets: self.chr_base_addrs,
+            chr_is_ram: self.chr_writeable,
+            nametables: self.nametable_sources.clone(),
+        }
+    }
+
     pub fn set_nametable_mirroring(&mut self, mirroring: NametableMirroring) {
-        use self::NtOffset::*;
+        use self::CiramPage::*;
 
-        self.nametable_base_addrs = match mirroring {
+        let pages = match mirroring {
             NametableMirroring::Horizontal => {
-                [Addr000, Addr000, Addr400, Addr400]
+                [Page0, Page0, Page1, Page1]
             }
             NametableMirroring::Vertical => {
-                [Addr000, Addr400, Addr000, Addr400]
+                [Page0, Page1, Page0, Page1]
             }
             NametableMirroring::SingleScreenLowerBank => {
-                [Addr000, Addr000, Addr000, Addr000]
+                [Page0, Page0, Page0, Page0]
             }
             NametableMirroring::SingleScreenUpperBank => {
-                [Addr400, Addr400, Addr400, Addr400]
+                [Page1, Page1, Page1, Page1]
             }
             NametableMirroring::FourScreen => {
-                [Addr000, Addr400, Addr800, AddrC00]
+                [Page0, Page1, Page2, Page3]
             }
         };
+        self.nametable_sources = pages.map(NametableSource::Ciram);
     }
+
+    /// Map one of the four nametables (0 = 0x2000, 1 = 0x2400, 2 = 0x2800, 3 = 0x2C00).
+    pub fn map_nametable(&mut self, nametable: usize, source: NametableSource) {
+        self.nametable_sources[nametable] = source;
+    }
+
+    /// Give the cart some RAM that can be mapped with `NametableSource::CartRam`.
+    pub fn alloc_cart_nametable_ram(&mut self, size: usize) {
+        self.cart_nametable_ram = vec![0; size].into_boxed_slice();
+    }
+
+    /// For mappers that also expose their nametable RAM to the CPU.
+    pub fn cart_nametable_ram_mut(&mut self) -> &mut [u8] { &mut self.cart_nametable_ram }
     
     pub fn map_prg_32k(&mut self, page_index: i32) {
         self.map_prg_range(0..4, page_index, 32 * 1024);
@@ -138,9 +205,15 @@ impl MemoryMap {
 impl MemoryMap {
     /// [addr] expected to be in range 0x8000..0xFFFF
     pub fn read_prg(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_rom_offset(addr)]
+    }
+
+    /// The offset into PRG ROM that [addr] is currently mapped to.
+    /// [addr] expected to be in range 0x8000..0xFFFF
+    pub fn prg_rom_offset(&self, addr: u16) -> usize {
         let bank_no = (addr as usize >> 0x1FFFu32.count_ones()) & 3;
         let base_addr = self.prg_base_addrs[bank_no];
-        self.prg_rom[base_addr + (addr as usize & 0x1FFF)]
+        base_addr + (addr as usize & 0x1FFF)
     }
 
     pub fn read_pattern_table(&self, addr: u16) -> u8 {
@@ -163,13 +236,32 @@ impl MemoryMap {
     }
 
     pub fn read_nametable(&self, addr: u16) -> u8 {
-        let offset: NtOffset = self.nametable_base_addrs[nt_addr_to_offset(addr)];
-        self.nametable_storage[offset as usize + (addr as usize & 0x3FF)]
+        let addr_in_page = addr as usize & 0x3FF;
+        match &self.nametable_sources[nt_addr_to_offset(addr)] {
+            NametableSource::Ciram(page) => self.ciram[*page as usize + addr_in_page],
+            NametableSource::CartRam(offset) => self.cart_nametable_ram[offset + addr_in_page],
+            NametableSource::Chr(offset) => self.chr_storage[(offset + addr_in_page) % self.chr_storage.len()],
+            NametableSource::Fill(fill) => fill(addr),
+        }
     }
 
     pub fn write_nametable(&mut self, addr: u16, value: u8) {
-        let offset: NtOffset = self.nametable_base_addrs[nt_addr_to_offset(addr)];
-        self.nametable_storage[offset as usize + (addr as usize & 0x3FF)] = value;
+        let addr_in_page = addr as usize & 0x3FF;
+        match &self.nametable_sources[nt_addr_to_offset(addr)] {
+            NametableSource::Ciram(page) => {
+                self.ciram[*page as usize + addr_in_page] = value;
+            }
+            NametableSource::CartRam(offset) => {
+                self.cart_nametable_ram[offset + addr_in_page] = value;
+            }
+            NametableSource::Chr(offset) if self.chr_writeable => {
+                let len = self.chr_storage.len();
+                self.chr_storage[(offset + addr_in_page) % len] = value;
+            }
+            NametableSource::Chr(_) | NametableSource::Fill(_) => {
+                mapper::out_of_bounds_write("read-only nametable", addr, value);
+            }
+        }
     }
 }
 
@@ -189,3 +281,29 @@ fn test_nametable_addr_to_offset() {
     assert_eq!(nt_addr_to_offset(0x2C00), 3);
     assert_eq!(nt_addr_to_offset(0x2FFF), 3);
 }
+
+#[test]
+fn test_nametable_sources() {
+    use crate::mapper::MapperDescriptor;
+
+    let cart = Cartridge::test_cart(MapperDescriptor::NROM, vec![0; 0x8000], CHR::ROM((0..0x2000).map(|i| (i / 0x400) as u8).collect()));
+    let mut map = MemoryMap::new(cart);
+    map.alloc_cart_nametable_ram(0x400);
+
+    map.write_nametable(0x2005, 0x11);
+    assert_eq!(map.read_nametable(0x2805), 0x11);
+
+    map.map_nametable(1, NametableSource::CartRam(0));
+    map.write_nametable(0x2405, 0x22);
+    assert_eq!(map.cart_nametable_ram_mut()[5], 0x22);
+    assert_eq!(map.read_nametable(0x2005), 0x11);
+
+    map.map_nametable(2, NametableSource::Chr(5 * 0x400));
+    assert_eq!(map.read_nametable(0x2800), 5);
+    map.write_nametable(0x2800, 0x33); // CHR ROM can't be written
+    assert_eq!(map.read_nametable(0x2800), 5);
+
+    map.map_nametable(3, NametableSource::Fill(Rc::new(|addr| if addr & 0x3FF >= 0x3C0 { 0xFF } else { 0x44 })));
+    assert_eq!(map.read_nametable(0x2C00), 0x44);
+    assert_eq!(map.read_nametable(0x2FC0), 0xFF);
+}
@@ -2,7 +2,7 @@ use log::{trace};
 use crate::cartridge::{NametableMirroring};
 use crate::mapper;
 use crate::mapper::memory_map::MemoryMap;
-use crate::mapper::RawMapper;
+use crate::mapper::{MapperRegister, RawMapper};
 
 /// Mapper 1: MMC1
 /// https://www.nesdev.org/wiki/MMC1
@@ -18,13 +18,13 @@ pub struct MMC1Mapper {
     shift_counter: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum CHRMode {
     Switch8KiB,
     SwitchTwo4KiB,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum PRGMode {
     Switch32KiB,
     FixedFirstSwitchLast,
@@ -163,4 +163,16 @@ impl RawMapper for MMC1Mapper {
             _ => mapper::out_of_bounds_write("CPU memory map", addr, value)
         }
     }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        vec![
+            MapperRegister::with_description("PRG mode", self.prg_mode as u8, format!("{:?}", self.prg_mode)),
+            MapperRegister::with_description("CHR mode", self.chr_mode as u8, format!("{:?}", self.chr_mode)),
+            MapperRegister::new("CHR bank 0", self.chr_bank_0),
+            MapperRegister::new("CHR bank 1", self.chr_bank_1),
+            MapperRegister::new("PRG bank", self.prg_bank),
+            MapperRegister::new("Shift register", self.shift_register),
+            MapperRegister::new("Shift count", self.shift_counter),
+        ]
+    }
 }
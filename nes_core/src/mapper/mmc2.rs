@@ -2,7 +2,7 @@ use std::cell::{Cell};
 use std::rc::Rc;
 use crate::cartridge::{NametableMirroring};
 use crate::mapper;
-use crate::mapper::{PPUPatternPostReadHook, RawMapper};
+use crate::mapper::{MapperRegister, PPUPatternPostReadHook, RawMapper};
 use crate::mapper::memory_map::MemoryMap;
 
 /// https://www.nesdev.org/wiki/MMC2
@@ -87,6 +87,19 @@ impl RawMapper for MMC2Mapper {
         }))
     }
 
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        let inner = &self.inner;
+        vec![
+            MapperRegister::new("PRG bank", inner.prg_bank.get()),
+            MapperRegister::new("CHR bank 0 FD", inner.chr_bank_0[BankSelector::FD as usize].get()),
+            MapperRegister::new("CHR bank 0 FE", inner.chr_bank_0[BankSelector::FE as usize].get()),
+            MapperRegister::new("CHR bank 1 FD", inner.chr_bank_1[BankSelector::FD as usize].get()),
+            MapperRegister::new("CHR bank 1 FE", inner.chr_bank_1[BankSelector::FE as usize].get()),
+            MapperRegister::with_description("Latch 0", inner.chr_selector_0.get() as u8, format!("{:?}", inner.chr_selector_0.get())),
+            MapperRegister::with_description("Latch 1", inner.chr_selector_1.get() as u8, format!("{:?}", inner.chr_selector_1.get())),
+        ]
+    }
+
     fn write_main_bus(&mut self, memory: &mut MemoryMap, addr: u16, value: u8) {
         let chr_bank_addr = value & 0b1_1111;
         match addr {
@@ -2,7 +2,7 @@ use std::rc::Rc;
 use log::{info};
 use crate::cartridge::{NametableMirroring};
 use crate::mapper;
-use crate::mapper::{RawMapper};
+use crate::mapper::{MapperRegister, RawMapper};
 use crate::mapper::memory_map::MemoryMap;
 use crate::nes::{InterruptSource, Signals};
 
@@ -23,7 +23,7 @@ pub struct MMC3Mapper {
     signals: Rc<Signals>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum PRGBankMode {
     /// $8000-$9FFF swappable, $C000-$DFFF fixed to second-last bank
     Swappable89 = 0,
@@ -172,6 +172,24 @@ impl RawMapper for MMC3Mapper {
         }
     }
 
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        const BANK_REG_NAMES: [&str; 8] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7"];
+        let mut registers: Vec<MapperRegister> = BANK_REG_NAMES.iter()
+            .zip(self.bank_reg)
+            .map(|(name, value)| MapperRegister::new(name, value))
+            .collect();
+        registers.extend([
+            MapperRegister::new("Bank select", self.bank_reg_select),
+            MapperRegister::with_description("PRG bank mode", self.prg_bank_mode as u8, format!("{:?}", self.prg_bank_mode)),
+            MapperRegister::new("CHR A12 inversion", self.chr_a12_inversion),
+            MapperRegister::new("IRQ counter", self.irq_counter),
+            MapperRegister::new("IRQ latch", self.irq_counter_reload_value),
+            MapperRegister::new("IRQ reload", self.irq_counter_reload),
+            MapperRegister::new("IRQ enable", self.irq_enable),
+        ]);
+        registers
+    }
+
     fn on_cycle_scanline(&mut self) {
         if self.irq_counter == 0 && self.irq_enable {
             self.signals.request_interrupt(InterruptSource::MMC3);
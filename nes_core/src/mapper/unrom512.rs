@@ -0,0 +1,348 @@
+use std::cell::Cell;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use log::{info, warn};
+use crate::cartridge::{Cartridge, NametableMirroring};
+use crate::mapper;
+use crate::mapper::{MapperRegister, PRGReadOverride, RawMapper};
+use crate::mapper::bus_conflicts::{BusConflictResolver, BusConflicts};
+use crate::mapper::memory_map::MemoryMap;
+
+/// Mapper 30: UNROM 512
+/// https://www.nesdev.org/wiki/UNROM_512
+///
+/// Up to 512K of PRG in 16K banks (the last bank is fixed at $C000), 32K of CHR RAM in 8K banks
+/// and optionally a software-selected one-screen mirroring.
+/// The self-flashable variant replaces the PRG ROM with an SST39SF040 flash chip, which the game
+/// can reprogram to save its progress.
+pub struct UnRom512Mapper {
+    prg_bank: u8,
+    chr_bank: u8,
+    /// Only used when the header asks for one-screen mirroring.
+    one_screen_upper: bool,
+    one_screen_mirroring: bool,
+
+    bus_conflicts: BusConflictResolver,
+    /// None if the board isn't self-flashable.
+    flash: Option<Rc<Flash>>,
+}
+
+impl UnRom512Mapper {
+    pub fn new(cart: &Cartridge) -> UnRom512Mapper {
+        // The battery flag marks a self-flashable board, these don't have bus conflicts.
+        // (Mapper 30's submappers are board variants, not the UxROM bus conflict setting.)
+        let flashable = cart.prg_ram_battery_backed;
+        let bus_conflicts = if flashable { BusConflicts::None } else { BusConflicts::And };
+        UnRom512Mapper {
+            prg_bank: 0,
+            chr_bank: 0,
+            one_screen_upper: false,
+            // The header's four-screen bit selects one-screen mirroring on this board.
+            // (The four-screen variant, which also sets the vertical bit, is rejected by parse_rom.)
+            one_screen_mirroring: matches!(cart.mirroring, NametableMirroring::FourScreen),
+
+            bus_conflicts: BusConflictResolver::new(bus_conflicts),
+            flash: if flashable { Some(Rc::new(Flash::new(cart.save_path.clone()))) } else { None },
+        }
+    }
+
+    fn sync_mappings(&self, memory: &mut MemoryMap) {
+        memory.map_prg_16k(0, self.prg_bank as i32);
+        memory.map_prg_16k(1, -1);
+
+        let chr_banks = (memory.chr_len() / 0x2000).max(1);
+        memory.map_chr_8k((self.chr_bank as usize % chr_banks) * 0x2000);
+
+        if self.one_screen_mirroring {
+            memory.set_nametable_mirroring(if self.one_screen_upper {
+                NametableMirroring::SingleScreenUpperBank
+            } else {
+                NametableMirroring::SingleScreenLowerBank
+            });
+        }
+    }
+
+    fn write_register(&mut self, memory: &mut MemoryMap, addr: u16, value: u8) {
+        // MCCP PPPP
+        let value = self.bus_conflicts.resolve(memory, addr, value);
+        self.prg_bank = value & 0b1_1111;
+        self.chr_bank = value >> 5 & 0b11;
+        self.one_screen_upper = value & 0x80 != 0;
+        self.sync_mappings(memory);
+    }
+}
+
+impl RawMapper for UnRom512Mapper {
+    fn init_memory_map(&self, memory: &mut MemoryMap) {
+        if let Some(flash) = self.flash.as_ref() {
+            flash.load(memory);
+        }
+        self.sync_mappings(memory);
+    }
+
+    fn write_main_bus(&mut self, memory: &mut MemoryMap, addr: u16, value: u8) {
+        match (addr, self.flash.as_ref()) {
+            // On the self-flashable board, $8000-$BFFF goes to the flash chip, and only $C000-$FFFF
+            // is the bank register.
+            (0x8000..=0xBFFF, Some(flash)) => {
+                flash.write(memory, addr, value);
+            }
+            (0x8000..=0xFFFF, _) => {
+                self.write_register(memory, addr, value);
+            }
+            _ => mapper::out_of_bounds_write("CPU memory map", addr, value),
+        }
+    }
+
+    fn get_prg_read_override(&self) -> Option<Rc<PRGReadOverride>> {
+        let flash: Rc<Flash> = Rc::clone(self.flash.as_ref()?);
+        Some(Rc::new(move |memory: &MemoryMap, addr: u16| {
+            flash.read_override(memory, addr)
+        }))
+    }
+
+    fn save_persistent_data(&mut self, memory: &MemoryMap) -> io::Result<()> {
+        match self.flash.as_ref() {
+            Some(flash) => flash.save(memory),
+            None => Ok(()),
+        }
+    }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        let mut registers = vec![
+            MapperRegister::new("PRG bank", self.prg_bank),
+            MapperRegister::new("CHR bank", self.chr_bank),
+            MapperRegister::new("One-screen upper", self.one_screen_upper),
+        ];
+        if let Some(flash) = self.flash.as_ref() {
+            let state = flash.state.get();
+            registers.push(MapperRegister::with_description("Flash state", state as u8, format!("{state:?}")));
+        }
+        registers
+    }
+}
+
+/// The SST39SF040 command state machine. Commands are written as unlock cycles to flash addresses
+/// $5555 and $2AAA, which the game reaches by selecting bank 1 or 0 and writing to $9555 or $AAAA.
+/// Programming and erasing complete instantly, we don't emulate the chip's busy/toggle status.
+/// http://ww1.microchip.com/downloads/en/DeviceDoc/20005022C.pdf
+struct Flash {
+    state: Cell<FlashState>,
+    /// Set when the PRG contents have changed since they were last saved.
+    dirty: Cell<bool>,
+    save_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FlashState {
+    Idle,
+    /// Got $AA at $5555
+    Unlock1,
+    /// Got $55 at $2AAA
+    Unlock2,
+    /// Got $A0 at $5555, the next write programs a byte
+    ByteProgram,
+    /// Got $80 at $5555, expecting another unlock sequence
+    EraseUnlock1,
+    /// Got $AA at $5555 after $80
+    EraseUnlock2,
+    /// Got $55 at $2AAA, the next write picks a sector or chip erase
+    EraseCommand,
+    /// Got $90 at $5555, reads return the manufacturer and device ID until reset with $F0
+    SoftwareId,
+}
+
+const MANUFACTURER_ID: u8 = 0xBF;
+const DEVICE_ID: u8 = 0xB7;
+const SECTOR_SIZE: usize = 0x1000;
+
+impl Flash {
+    fn new(save_path: Option<PathBuf>) -> Flash {
+        Flash {
+            state: Cell::new(FlashState::Idle),
+            dirty: Cell::new(false),
+            save_path,
+        }
+    }
+
+    fn write(&self, memory: &mut MemoryMap, addr: u16, value: u8) {
+        use self::FlashState::*;
+
+        let offset = memory.prg_rom_offset(addr);
+        // The chip only decodes A0-A14 for command cycles.
+        let command_addr = offset & 0x7FFF;
+
+        if value == 0xF0 && self.state.get() != ByteProgram {
+            // Software ID exit / reset, accepted in any state
+            self.state.set(Idle);
+            return;
+        }
+
+        let next_state = match (self.state.get(), command_addr, value) {
+            (Idle | SoftwareId, 0x5555, 0xAA) => Unlock1,
+            (Unlock1, 0x2AAA, 0x55) => Unlock2,
+            (Unlock2, 0x5555, 0xA0) => ByteProgram,
+            (Unlock2, 0x5555, 0x80) => EraseUnlock1,
+            (Unlock2, 0x5555, 0x90) => SoftwareId,
+            (EraseUnlock1, 0x5555, 0xAA) => EraseUnlock2,
+            (EraseUnlock2, 0x2AAA, 0x55) => EraseCommand,
+            (ByteProgram, _, _) => {
+                // Programming can only clear bits, only an erase can set them again.
+                memory.prg_rom_mut()[offset] &= value;
+                self.dirty.set(true);
+                Idle
+            }
+            (EraseCommand, _, 0x30) => {
+                let sector_start = offset & !(SECTOR_SIZE - 1);
+                memory.prg_rom_mut()[sector_start..sector_start + SECTOR_SIZE].fill(0xFF);
+                self.dirty.set(true);
+                Idle
+            }
+            (EraseCommand, 0x5555, 0x10) => {
+                memory.prg_rom_mut().fill(0xFF);
+                self.dirty.set(true);
+                Idle
+            }
+            (SoftwareId, _, _) => SoftwareId,
+            _ => Idle,
+        };
+        self.state.set(next_state);
+    }
+
+    fn read_override(&self, memory: &MemoryMap, addr: u16) -> Option<u8> {
+        if self.state.get() != FlashState::SoftwareId {
+            return None;
+        }
+        if memory.prg_rom_offset(addr) & 1 == 0 {
+            Some(MANUFACTURER_ID)
+        } else {
+            Some(DEVICE_ID)
+        }
+    }
+
+    fn load(&self, memory: &mut MemoryMap) {
+        let Some(save_path) = self.save_path.as_ref() else { return; };
+        let Ok(contents) = std::fs::read(save_path) else { return; };
+        if contents.len() != memory.prg_rom_len() {
+            warn!("Ignoring flash save {}, expected {} bytes but got {}", save_path.display(), memory.prg_rom_len(), contents.len());
+            return;
+        }
+        info!("Loaded flash save from {}", save_path.display());
+        memory.prg_rom_mut().copy_from_slice(&contents);
+    }
+
+    fn save(&self, memory: &MemoryMap) -> io::Result<()> {
+        if !self.dirty.get() {
+            return Ok(());
+        }
+        let Some(save_path) = self.save_path.as_ref() else { return Ok(()); };
+        std::fs::write(save_path, memory.prg_rom())?;
+        info!("Saved flash contents to {}", save_path.display());
+        self.dirty.set(false);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_flash_program_and_erase() {
+    use crate::cartridge::CHR;
+    use crate::mapper::MapperDescriptor;
+
+    let cart = Cartridge {
+        prg_ram_battery_backed: true,
+        mirroring: NametableMirroring::FourScreen,
+        ..Cartridge::test_cart(MapperDescriptor::UNROM512, vec![0xFF; 512 * 1024], CHR::RAM(32 * 1024))
+    };
+    let mut memory = MemoryMap::new(cart.clone());
+    let mut mapper = UnRom512Mapper::new(&cart);
+    mapper.init_memory_map(&mut memory);
+
+    fn command(mapper: &mut UnRom512Mapper, memory: &mut MemoryMap, bank: u8, addr: u16, value: u8) {
+        mapper.write_main_bus(memory, 0xC000, bank);
+        mapper.write_main_bus(memory, addr, value);
+    }
+
+    // Program $12 to flash address $4_0123 (bank 16)
+    command(&mut mapper, &mut memory, 1, 0x9555, 0xAA);
+    command(&mut mapper, &mut memory, 0, 0xAAAA, 0x55);
+    command(&mut mapper, &mut memory, 1, 0x9555, 0xA0);
+    command(&mut mapper, &mut memory, 16, 0x8123, 0x12);
+    assert_eq!(memory.prg_rom()[0x4_0123], 0x12);
+    assert_eq!(memory.read_prg(0x8123), 0x12);
+
+    // A write without the unlock sequence doesn't program anything
+    command(&mut mapper, &mut memory, 16, 0x8124, 0x00);
+    assert_eq!(memory.prg_rom()[0x4_0124], 0xFF);
+
+    // Software ID mode
+    command(&mut mapper, &mut memory, 1, 0x9555, 0xAA);
+    command(&mut mapper, &mut memory, 0, 0xAAAA, 0x55);
+    command(&mut mapper, &mut memory, 1, 0x9555, 0x90);
+    let read_override = mapper.get_prg_read_override().unwrap();
+    assert_eq!(read_override(&memory, 0x8000), Some(MANUFACTURER_ID));
+    assert_eq!(read_override(&memory, 0x8001), Some(DEVICE_ID));
+    mapper.write_main_bus(&mut memory, 0x8000, 0xF0);
+    assert_eq!(read_override(&memory, 0x8000), None);
+
+    // Sector erase
+    command(&mut mapper, &mut memory, 1, 0x9555, 0xAA);
+    command(&mut mapper, &mut memory, 0, 0xAAAA, 0x55);
+    command(&mut mapper, &mut memory, 1, 0x9555, 0x80);
+    command(&mut mapper, &mut memory, 1, 0x9555, 0xAA);
+    command(&mut mapper, &mut memory, 0, 0xAAAA, 0x55);
+    command(&mut mapper, &mut memory, 16, 0x8000, 0x30);
+    assert_eq!(memory.prg_rom()[0x4_0123], 0xFF);
+}
+
+#[test]
+fn test_bus_conflicts() {
+    use crate::cartridge::CHR;
+    use crate::mapper::MapperDescriptor;
+
+    // Without flash the board has bus conflicts, whatever the submapper
+    let cart = Cartridge {
+        submapper: 1,
+        ..Cartridge::test_cart(MapperDescriptor::UNROM512, vec![0x01; 512 * 1024], CHR::RAM(32 * 1024))
+    };
+    let mut memory = MemoryMap::new(cart.clone());
+    let mut mapper = UnRom512Mapper::new(&cart);
+    mapper.init_memory_map(&mut memory);
+    mapper.write_main_bus(&mut memory, 0xC000, 3);
+    assert_eq!(memory.prg_rom_offset(0x8000), 0x4000);
+}
+
+#[test]
+fn test_flash_save() {
+    use crate::cartridge::CHR;
+    use crate::mapper::MapperDescriptor;
+
+    let save_path = std::env::temp_dir().join(format!("nes_core_test_flash_save_{}.sav", std::process::id()));
+    let cart = Cartridge {
+        prg_ram_battery_backed: true,
+        save_path: Some(save_path.clone()),
+        ..Cartridge::test_cart(MapperDescriptor::UNROM512, vec![0xFF; 512 * 1024], CHR::RAM(32 * 1024))
+    };
+    let mut memory = MemoryMap::new(cart.clone());
+    let mut mapper = UnRom512Mapper::new(&cart);
+    mapper.init_memory_map(&mut memory);
+    mapper.write_main_bus(&mut memory, 0xC000, 1);
+    mapper.write_main_bus(&mut memory, 0x9555, 0xAA);
+    mapper.write_main_bus(&mut memory, 0xC000, 0);
+    mapper.write_main_bus(&mut memory, 0xAAAA, 0x55);
+    mapper.write_main_bus(&mut memory, 0xC000, 1);
+    mapper.write_main_bus(&mut memory, 0x9555, 0xA0);
+    mapper.write_main_bus(&mut memory, 0x8000, 0x12);
+
+    mapper.save_persistent_data(&memory).unwrap();
+    let saved = std::fs::read(&save_path).unwrap();
+    std::fs::remove_file(&save_path).unwrap();
+    assert_eq!(saved[0x4000], 0x12);
+
+    // Errors are reported, and it tries again next time
+    let mut unwritable_mapper = UnRom512Mapper::new(&Cartridge { save_path: Some(std::env::temp_dir()), ..cart });
+    let flash = Rc::clone(unwritable_mapper.flash.as_ref().unwrap());
+    flash.dirty.set(true);
+    assert!(unwritable_mapper.save_persistent_data(&memory).is_err());
+    assert!(flash.dirty.get());
+}
@@ -1,14 +1,22 @@
-use crate::mapper::{RawMapper};
+use crate::cartridge::Cartridge;
+use crate::mapper;
+use crate::mapper::{MapperRegister, RawMapper};
+use crate::mapper::bus_conflicts::{BusConflictResolver, BusConflicts};
 use crate::mapper::memory_map::MemoryMap;
 
 /// Mapper 2: UxROM
 /// https://www.nesdev.org/wiki/UxROM
 pub struct UxRomMapper {
+    prg_bank: u8,
+    bus_conflicts: BusConflictResolver,
 }
 
 impl UxRomMapper {
-    pub fn new() -> UxRomMapper {
+    pub fn new(cart: &Cartridge) -> UxRomMapper {
         UxRomMapper {
+            prg_bank: 0,
+            // UNROM and UOROM boards have bus conflicts.
+            bus_conflicts: BusConflictResolver::new(BusConflicts::for_submapper(cart.submapper, BusConflicts::And)),
         }
     }
 }
@@ -20,7 +28,17 @@ impl RawMapper for UxRomMapper {
         memory.map_prg_16k(1, -1);
     }
 
-    fn write_main_bus(&mut self, memory: &mut MemoryMap, _addr: u16, value: u8) {
+    fn write_main_bus(&mut self, memory: &mut MemoryMap, addr: u16, value: u8) {
+        if addr < 0x8000 {
+            mapper::out_of_bounds_write("CPU memory map", addr, value);
+            return;
+        }
+        let value = self.bus_conflicts.resolve(memory, addr, value);
+        self.prg_bank = value;
         memory.map_prg_16k(0, value as i32);
     }
+
+    fn describe_registers(&self) -> Vec<MapperRegister> {
+        vec![MapperRegister::new("PRG bank", self.prg_bank)]
+    }
 }
@@ -12,7 +12,6 @@ use crate::ppu::PPU;
 
 #[allow(non_snake_case)]
 pub struct NES {
-    target_cycles: u64,
     total_cycles: u64,
 
     pub A: u8,
@@ -85,7 +84,9 @@ impl Signals {
     }
 }
 
-pub const CYCLES_PER_FRAME: u64 = 29829;
+/// The average length of an NTSC frame, 341 * 262 - 0.5 PPU dots, in CPU cycles.
+/// Frames aren't a fixed number of CPU cycles, so this is only for estimating rates.
+pub const CYCLES_PER_FRAME: u64 = 29781;
 
 /// https://www.nesdev.org/wiki/Status_flags
 #[allow(non_snake_case)]
@@ -164,7 +165,6 @@ impl NES {
             PC: 0,
             SR: StatusRegister::from_byte(0),
             ram: [0; 0x800],
-            target_cycles: 0,
             total_cycles: 0,
             trace_instructions: log::log_enabled!(Trace),
             ppu: PPU::new(Rc::clone(&mapper), Rc::clone(&signals)),
@@ -183,8 +183,6 @@ impl NES {
     }
 
     pub fn power_on(&mut self) {
-        self.target_cycles = self.total_cycles;
-
         self.SR = StatusRegister::from_byte(0);
         self.A = 0;
         self.X = 0;
@@ -199,9 +197,19 @@ impl NES {
         self.apu.write_status_register(0x00);
     }
 
+    /// Pressing the console's reset button. Unlike power_on, RAM and most mapper state survive.
+    pub fn reset(&mut self) {
+        self.mapper.on_soft_reset();
+        // The APU sees the reset as it starts, rather than once the CPU's reset sequence is done
+        self.apu.reset();
+        self.do_reset_interrupt();
+    }
+
+    /// Runs until the PPU finishes the current frame. Frames vary in length: odd frames are a dot
+    /// shorter when rendering is enabled, and a CPU instruction can run a few dots past the end.
     pub fn simulate_frame(&mut self) {
-        self.target_cycles += CYCLES_PER_FRAME;
-        while self.target_cycles > self.total_cycles {
+        let frame_num = self.ppu.frame_num();
+        while self.ppu.frame_num() == frame_num {
             if self.signals.is_any_active() {
                 self.handle_interrupt();
             }
@@ -264,10 +272,43 @@ impl NES {
     }
 
     pub fn read8(&mut self, addr: u16) -> u8 {
+        if self.apu.is_dmc_dma_pending() {
+            self.do_dmc_dma(addr);
+        }
         self.tick();
         self.read8_no_tick(addr)
     }
 
+    /// The DMC's DMA halts the CPU on its next read cycle (writes can't be halted), so a DMA takes
+    /// 3 or 4 cycles: the halt, a dummy cycle, an optional alignment cycle, then the read itself,
+    /// which has to happen on a "get" cycle.
+    /// While halted the CPU repeats the read it was trying to do, which has side effects on
+    /// registers like $2007 and $4016 (it's why DPCM samples can corrupt controller reads).
+    /// https://www.nesdev.org/wiki/DMA#DMC_DMA
+    fn do_dmc_dma(&mut self, halted_addr: u16) {
+        self.tick();
+        self.read8_no_tick(halted_addr);
+        self.tick();
+        if !self.is_next_cycle_get() {
+            self.tick();
+        }
+        self.tick();
+        self.apu.do_dmc_dma();
+    }
+
+    /// A DMC DMA during an OAM DMA only takes over one of its get cycles, then needs one more
+    /// cycle to realign the OAM DMA, so it only stalls for 2 cycles.
+    pub(crate) fn do_dmc_dma_during_oam_dma(&mut self) {
+        self.tick();
+        self.apu.do_dmc_dma();
+        self.tick();
+    }
+
+    /// DMA reads happen on "get" cycles, and writes on "put" cycles, which alternate.
+    fn is_next_cycle_get(&self) -> bool {
+        self.total_cycles & 1 == 0
+    }
+
     pub fn read8_no_tick(&mut self, addr: u16) -> u8 {
         match addr {
             0x4020..=0xFFFF =>
@@ -362,3 +403,161 @@ impl NES {
         self.total_cycles
     }
 }
+
+#[cfg(test)]
+fn new_test_nes() -> NES {
+    use crate::cartridge::CHR;
+    use crate::mapper::MapperDescriptor;
+
+    NES::from_cart(Cartridge::test_cart(MapperDescriptor::NROM, vec![0; 0x8000], CHR::ROM(vec![0; 0x2000].into_boxed_slice())))
+}
+
+/// Starts playing a one byte sample, so the DMC wants a DMA straight away.
+#[cfg(test)]
+fn start_dmc_sample(nes: &mut NES, control: u8) {
+    nes.write8(0x4010, control);
+    nes.write8(0x4012, 0x00);
+    nes.write8(0x4013, 0x00);
+    nes.write8(0x4015, 0x10);
+}
+
+#[test]
+fn test_dmc_dma_stall() {
+    // The get cycles are the odd ones, so starting on an even cycle there's no alignment cycle
+    for (start_cycle_parity, expected_stall) in [(0, 3), (1, 4)] {
+        let mut nes = new_test_nes();
+        start_dmc_sample(&mut nes, 0x0F);
+        assert!(nes.apu.is_dmc_dma_pending());
+        if nes.get_cycles() & 1 != start_cycle_parity {
+            nes.tick();
+        }
+
+        let cycles = nes.get_cycles();
+        nes.read8(0x0000);
+        assert_eq!(nes.get_cycles() - cycles - 1, expected_stall, "Starting on cycle {cycles}");
+        assert!(!nes.apu.is_dmc_dma_pending());
+    }
+}
+
+#[test]
+fn test_dmc_dma_corrupts_controller_read() {
+    use crate::input::JoypadButtons;
+
+    let mut nes = new_test_nes();
+    nes.input.update_p1_key_state(JoypadButtons::B);
+    nes.write8(0x4016, 1);
+    nes.write8(0x4016, 0);
+    start_dmc_sample(&mut nes, 0x0F);
+
+    // The halted read clocks the controller, so the A button's bit is skipped
+    assert_eq!(1, nes.read8(0x4016));
+    assert_eq!(0, nes.read8(0x4016));
+}
+
+#[test]
+fn test_dmc_dma_during_oam_dma() {
+    // OAM DMA takes 513 cycles, plus an alignment cycle if it starts on an odd cycle
+    let do_oam_dma = |nes: &mut NES| {
+        let cycles = nes.get_cycles();
+        nes.write8(0x4014, 0x02);
+        (cycles, nes.get_cycles() - cycles - 1)
+    };
+    for start_cycle_parity in [0, 1] {
+        let mut nes = new_test_nes();
+        if nes.get_cycles() & 1 != start_cycle_parity {
+            nes.tick();
+        }
+        let (cycles, dma_cycles) = do_oam_dma(&mut nes);
+        assert_eq!(dma_cycles, 513 + (cycles & 1));
+    }
+
+    // A looping sample at the fastest rate empties the sample buffer every 432 cycles. Land the
+    // next DMC DMA at different points in the middle of the OAM DMA, on both get and put cycles.
+    for offset in 0..4 {
+        let mut nes = new_test_nes();
+        for i in 0..=255 {
+            nes.write8(0x0200 + i, i as u8);
+        }
+        start_dmc_sample(&mut nes, 0x4F);
+        nes.read8(0x0000);
+        while !nes.apu.is_dmc_dma_pending() {
+            nes.tick();
+        }
+        let buffer_emptied = nes.get_cycles();
+        nes.read8(0x0000);
+        while nes.get_cycles() < buffer_emptied + 432 - 200 - offset {
+            nes.tick();
+        }
+
+        // The DMC takes over one get cycle, then one more cycle realigns the OAM DMA
+        let (cycles, dma_cycles) = do_oam_dma(&mut nes);
+        assert_eq!(dma_cycles, 515 + (cycles & 1), "Offset {offset}");
+        assert!(!nes.apu.is_dmc_dma_pending());
+        nes.write8(0x2003, 0x80);
+        assert_eq!(nes.read8(0x2004), 0x80);
+    }
+}
+
+/// Runs one of blargg's test ROMs, which report their result in PRG RAM at $6000.
+/// https://github.com/christopherpow/nes-test-roms/blob/master/apu_test/readme.txt
+#[cfg(test)]
+fn run_blargg_rom(path: &std::path::Path) -> Result<(), String> {
+    let cart = crate::cartridge::parse_rom(path).map_err(|e| e.to_string())?;
+    let mut nes = NES::from_cart(Cartridge { save_path: None, ..cart });
+    nes.power_on();
+
+    let mut reset_frame = None;
+    for frame in 0..60 * 60 {
+        nes.simulate_frame();
+        // The result is only valid once the signature is written
+        if [0x6001, 0x6002, 0x6003].map(|addr| nes.mapper.read_main_bus(addr)) != [0xDE, 0xB0, 0x61] {
+            continue;
+        }
+        match nes.mapper.read_main_bus(0x6000) {
+            // Still running
+            0x80 => {}
+            // Wants the reset button pressed, at least 100ms from now
+            0x81 => match reset_frame {
+                None => reset_frame = Some(frame + 10),
+                Some(reset) if frame >= reset => {
+                    nes.reset();
+                    reset_frame = None;
+                }
+                Some(_) => {}
+            },
+            0x00 => return Ok(()),
+            code => {
+                let text: Vec<u8> = (0x6004..0x7000)
+                    .map(|addr| nes.mapper.read_main_bus(addr))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                return Err(format!("failed with code {code}: {}", String::from_utf8_lossy(&text).trim()));
+            }
+        }
+    }
+    Err("timed out".to_string())
+}
+
+/// The ROMs aren't in the repo: put the apu_test and apu_reset folders from nes-test-roms in
+/// ROMS/blargg, then run with `cargo test -- --ignored blargg`.
+#[test]
+#[ignore]
+fn test_blargg_apu_roms() {
+    let blargg_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../ROMS/blargg");
+    let mut failures = Vec::new();
+    for suite in ["apu_test/rom_singles", "apu_reset"] {
+        let suite_dir = blargg_dir.join(suite);
+        let entries = std::fs::read_dir(&suite_dir).unwrap_or_else(|e| panic!("Can't read {}: {e}", suite_dir.display()));
+        let mut roms: Vec<_> = entries
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "nes"))
+            .collect();
+        roms.sort();
+        for rom in roms {
+            if let Err(message) = run_blargg_rom(&rom) {
+                failures.push(format!("{}: {message}", rom.display()));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -1,6 +1,17 @@
 use std::rc::Rc;
+use bitflags::bitflags;
 use crate::mapper::Mapper;
 use crate::nes::{InterruptSource, NES, Signals};
+use crate::ppu::sprite_eval::SpriteEvaluation;
+
+mod ntsc_filter;
+mod ntsc_palette;
+mod palette;
+mod sprite_eval;
+
+pub use crate::ppu::ntsc_filter::{NTSC_OUTPUT_PIXELS, NTSC_OUTPUT_WIDTH, NtscFilter, NtscFilterPreset, NtscFilterSettings};
+pub use crate::ppu::ntsc_palette::{generate_ntsc_palette, NtscPaletteParams};
+pub use crate::ppu::palette::{BuiltinPalette, Palette, PALETTE_COLORS};
 
 const PPUCTRL: u16 = 0x2000;
 const PPUMASK: u16 = 0x2001;
@@ -11,6 +22,26 @@ const PPUSCROLL: u16 = 0x2005;
 const PPUADDR: u16 = 0x2006;
 const PPUDATA: u16 = 0x2007;
 
+bitflags! {
+    /// Obscure hardware behaviours that are off by default. Few games depend on them, and some
+    /// (like the decay) vary between consoles and with temperature.
+    pub struct PPUAccuracy : u8 {
+        /// Each bit of the open bus latch decays to 0 about 600ms after it was last driven high.
+        const OPEN_BUS_DECAY = 0x01;
+        /// If OAMADDR isn't 0 when rendering starts, part of OAM is copied over sprites 0 and 1.
+        const OAM_ADDR_CORRUPTION = 0x02;
+        /// OAM is DRAM, refreshed by rendering. With rendering off, it's lost after a couple of milliseconds.
+        const OAM_DECAY = 0x04;
+    }
+}
+
+/// How long a bit of the open bus latch holds its value, in PPU dots. About 600ms.
+const OPEN_BUS_DECAY_DOTS: u64 = 3_200_000;
+/// How long a row of OAM holds its value without being refreshed, in PPU dots. 3000 CPU cycles, about 1.7ms.
+const OAM_DECAY_DOTS: u64 = 9000;
+/// The value decayed OAM reads as.
+const OAM_DECAYED_VALUE: u8 = 0x10;
+
 pub struct PPU {
     control: PPUControl,
     mask: PPUMask,
@@ -26,30 +57,49 @@ pub struct PPU {
      */
     v_addr: u16,
     t_addr: u16,
+    /// A PPUADDR write during rendering, and the dots until it's copied to v
+    pending_v_addr: Option<(u16, u8)>,
     // https://www.nesdev.org/wiki/PPU_scrolling
     fine_x: u8,
     write_toggle_w: bool,
     data_bus_latch: u8,
+    /// When each bit of data_bus_latch was last set to 1, in PPU dots
+    data_bus_refresh_dots: [u64; 8],
+    /// PPUDATA reads return the byte read by the previous PPUDATA read
+    data_read_buffer: u8,
+    accuracy: PPUAccuracy,
 
     oam_addr: u8,
     oam: [u8; NUM_SPRITES * 4],
+    /// When each 8 byte row of OAM was last accessed or refreshed, in PPU dots
+    oam_refresh_dots: [u64; NUM_SPRITES * 4 / 8],
     cur_line_sprites: [SpriteRowSlice; 8],
     cur_line_num_sprites: usize, // Between 0 and 8
     sprite_0_hit: bool,
+    sprite_overflow: bool,
+    sprite_eval: SpriteEvaluation,
+    /// What OAMDATA reads return while rendering, the OAM or secondary OAM byte the PPU last accessed
+    oam_data_bus: u8,
 
     palettes: [u8; 2 * 4 * 4],
     mapper: Rc<Mapper>,
 
     vblank_started: bool,
+    /// Set by a PPUSTATUS read on the dot before vblank starts, which stops the flag being set that frame
+    suppress_vblank: bool,
     signals: Rc<Signals>,
 
-    /// Filled with values 0-63, which are indices into "ntscpalette_24bpp.pal".
-    /// This is the in-progress frame that is being drawn.
-    cur_display_buffer: Box<[u8; SCREEN_PIXELS]>,
-    /// Filled with values 0-63, which are indices into "ntscpalette_24bpp.pal".
+    /// Filled with values 0-511: a palette index 0-63, with the three PPUMASK color emphasis bits
+    /// above it. Turned into colors by a `Palette`. This is the in-progress frame that is being drawn.
+    cur_display_buffer: Box<[u16; SCREEN_PIXELS]>,
+    /// Filled with values 0-511, like cur_display_buffer.
     /// This is the finished frame, ready to be displayed.
-    finished_display_buffer: Box<[u8; SCREEN_PIXELS]>,
+    finished_display_buffer: Box<[u16; SCREEN_PIXELS]>,
+    /// The frame_num of finished_display_buffer
+    finished_frame_num: u64,
     frame_num: u64,
+    /// PPU dots since power on, for timing the decay of the open bus and OAM
+    total_dots: u64,
 
     dot: u32, // 0-340
     scanline: u32, // 0-261
@@ -57,6 +107,11 @@ pub struct PPU {
     tiles_palette_hi: u16,
     tiles_lo: u16,
     tiles_hi: u16,
+    // The background tile being fetched, loaded into the shift registers every 8 dots
+    next_tile_index: u8,
+    next_tile_palette: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
 }
 
 impl PPU {
@@ -67,25 +122,36 @@ impl PPU {
 
             v_addr: 0,
             t_addr: 0,
+            pending_v_addr: None,
             fine_x: 0,
             write_toggle_w: false,
             data_bus_latch: 0,
+            data_bus_refresh_dots: [0; 8],
+            data_read_buffer: 0,
+            accuracy: PPUAccuracy::empty(),
 
             oam_addr: 0,
             oam: [0; NUM_SPRITES * 4],
+            oam_refresh_dots: [0; NUM_SPRITES * 4 / 8],
             cur_line_sprites: [SpriteRowSlice::hidden(); 8],
             cur_line_num_sprites: 0,
             sprite_0_hit: false,
+            sprite_overflow: false,
+            sprite_eval: SpriteEvaluation::new(),
+            oam_data_bus: 0xFF,
 
             palettes: [0; 2 * 4 * 4],
             mapper,
 
             vblank_started: true,
+            suppress_vblank: false,
             signals,
 
             cur_display_buffer: Box::new([0; 256 * 240]),
             finished_display_buffer: Box::new([0; 256 * 240]),
+            finished_frame_num: 0,
             frame_num: 0,
+            total_dots: 0,
 
             dot: 0,
             scanline: 0,
@@ -93,13 +159,72 @@ impl PPU {
             tiles_palette_hi: 0,
             tiles_lo: 0,
             tiles_hi: 0,
+            next_tile_index: 0,
+            next_tile_palette: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
         }
     }
 
+    /// The number of frames the PPU has finished, counted at the end of the pre-render line.
+    pub fn frame_num(&self) -> u64 {
+        self.frame_num
+    }
+
+    pub fn set_accuracy(&mut self, accuracy: PPUAccuracy) {
+        self.accuracy = accuracy;
+    }
+
     fn rendering_enabled(&self) -> bool {
         self.mask.show_background_or_sprites
     }
 
+    /// Whether the PPU is rendering right now: rendering is enabled, and it's on a visible line or the pre-render line
+    fn is_rendering(&self) -> bool {
+        self.rendering_enabled() && (self.scanline < 240 || self.scanline == LAST_SCANLINE)
+    }
+
+    /// Sets the bits of the open bus latch selected by `mask`, which are being driven.
+    fn drive_data_bus(&mut self, value: u8, mask: u8) {
+        self.data_bus_latch = (self.data_bus_latch & !mask) | (value & mask);
+        for (bit, refresh_dots) in self.data_bus_refresh_dots.iter_mut().enumerate() {
+            if mask & value & (1 << bit) != 0 {
+                *refresh_dots = self.total_dots;
+            }
+        }
+    }
+
+    /// The open bus latch, after any bits have decayed.
+    fn read_data_bus(&mut self) -> u8 {
+        if self.accuracy.contains(PPUAccuracy::OPEN_BUS_DECAY) {
+            for (bit, refresh_dots) in self.data_bus_refresh_dots.iter().enumerate() {
+                if self.total_dots - refresh_dots > OPEN_BUS_DECAY_DOTS {
+                    self.data_bus_latch &= !(1 << bit);
+                }
+            }
+        }
+        self.data_bus_latch
+    }
+
+    /// The CPU is accessing OAM. With rendering off, the row being accessed may have decayed since
+    /// it was last refreshed.
+    fn access_oam_row(&mut self, addr: u8) {
+        let row = addr as usize / 8;
+        if self.accuracy.contains(PPUAccuracy::OAM_DECAY) && !self.rendering_enabled()
+            && self.total_dots - self.oam_refresh_dots[row] > OAM_DECAY_DOTS {
+            self.oam[row * 8..(row + 1) * 8].fill(OAM_DECAYED_VALUE);
+        }
+        self.oam_refresh_dots[row] = self.total_dots;
+    }
+
+    /// Rendering is being turned on, and will refresh all of OAM from now on. Rows that weren't
+    /// accessed while rendering was off have decayed.
+    fn decay_oam(&mut self) {
+        for row in 0..self.oam_refresh_dots.len() {
+            self.access_oam_row((row * 8) as u8);
+        }
+    }
+
     fn write_mem(&mut self, addr: u16, val: u8) {
         // PPU address bus is 14 bits, mask out the upper bits
         match addr & 0x3FFF {
@@ -133,25 +258,30 @@ impl PPU {
     }
 
     fn flip_frame(&mut self) {
-        self.finished_display_buffer.copy_from_slice(&self.cur_display_buffer[..])
+        self.finished_display_buffer.copy_from_slice(&self.cur_display_buffer[..]);
+        self.finished_frame_num = self.frame_num;
     }
 
-    pub fn output_display_buffer_rgb(&self, output: &mut [Color; SCREEN_PIXELS]) {
+    pub fn output_display_buffer_rgb(&self, palette: &Palette, output: &mut [Color; SCREEN_PIXELS]) {
         for (i, palette_index) in self.finished_display_buffer.iter().enumerate() {
-            // wrap into range of 64 colors
-            output[i] = get_output_color(*palette_index);
+            output[i] = palette.color(*palette_index);
         }
     }
 
-    pub fn output_display_buffer_u32_argb(&self, output: &mut [u32; SCREEN_PIXELS]) {
+    pub fn output_display_buffer_u32_argb(&self, palette: &Palette, output: &mut [u32; SCREEN_PIXELS]) {
         for (i, palette_index) in self.finished_display_buffer.iter().enumerate() {
-            // wrap into range of 64 colors
-            let Color { r, g, b } = get_output_color(*palette_index);
+            let Color { r, g, b } = palette.color(*palette_index);
             output[i] = (r as u32) << 16 | (g as u32) << 8 | (b as u32);
         }
     }
 
-    pub fn output_display_buffer_indexed(&self, output: &mut[u8; SCREEN_PIXELS]) {
+    /// Outputs the frame through an NTSC video filter, `NTSC_OUTPUT_WIDTH` pixels wide.
+    pub fn output_display_buffer_ntsc(&self, filter: &mut NtscFilter, palette: &Palette, output: &mut [u32; NTSC_OUTPUT_PIXELS]) {
+        filter.apply(&self.finished_display_buffer, self.finished_frame_num, palette, output);
+    }
+
+    /// Outputs 9-bit palette indices, with the color emphasis bits above the 6-bit color.
+    pub fn output_display_buffer_indexed(&self, output: &mut [u16; SCREEN_PIXELS]) {
         output.copy_from_slice(&self.finished_display_buffer[..])
     }
 }
@@ -167,27 +297,6 @@ pub struct Color {
     pub b: u8,
 }
 
-static PALETTE_LOOKUP: &[u8; 192] = include_bytes!("../../nestopia_rgb.pal");
-
-fn get_output_color(palette_index: u8) -> Color {
-    let r = PALETTE_LOOKUP[palette_index as usize * 3 + 0];
-    let g = PALETTE_LOOKUP[palette_index as usize * 3 + 1];
-    let b = PALETTE_LOOKUP[palette_index as usize * 3 + 2];
-
-    Color { r, g, b }
-}
-
-pub fn get_palette_colors() -> [Color; 64] {
-    let mut res = [Color::default(); 64];
-    for i in 0..64 {
-        let r = PALETTE_LOOKUP[i * 3 + 0];
-        let g = PALETTE_LOOKUP[i * 3 + 1];
-        let b = PALETTE_LOOKUP[i * 3 + 2];
-        res[i] = Color { r, g, b };
-    }
-    res
-}
-
 fn mask_palette_addr(addr: u16) -> usize {
     if addr == 0x3F10 {
         0
@@ -245,7 +354,6 @@ impl PPUControl {
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 struct PPUMask {
     /// Bit 0 controls a greyscale mode, which causes the palette to use only the colors from the
@@ -259,9 +367,9 @@ struct PPUMask {
     show_background: bool,
     show_sprites: bool,
     show_background_or_sprites: bool,
-    emphasize_red: bool,
-    emphasize_green: bool,
-    emphasize_blue: bool,
+    /// Bits 5-7 emphasize red, green and blue, by darkening the other colors. They're kept in
+    /// bits 6-8 here, ready to be ORed with the palette index into the display buffer.
+    emphasis_bits: u16,
 }
 
 impl PPUMask {
@@ -273,9 +381,7 @@ impl PPUMask {
             show_background: val & 0b0000_1000 != 0,
             show_sprites: val & 0b0001_0000 != 0,
             show_background_or_sprites: val & 0b0001_1000 != 0,
-            emphasize_red: val & 0b0010_0000 != 0,
-            emphasize_green: val & 0b0100_0000 != 0,
-            emphasize_blue: val & 0b1000_0000 != 0,
+            emphasis_bits: ((val & 0b1110_0000) as u16) << 1,
          }
     }
 }
@@ -289,8 +395,15 @@ impl PPU {
                 ppu.write_toggle_w = false;
 
                 let mut status = 0u8;
-                // TODO: Sprite overflow not implemented
-
+                // The vblank flag is set on dot 1 of line 241, and reading it races with that.
+                // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+                if ppu.scanline == VBLANK_SCANLINE && ppu.dot == 1 {
+                    // Reading on the dot before it's set returns a clear flag, and it stays clear
+                    ppu.suppress_vblank = true;
+                } else if ppu.scanline == VBLANK_SCANLINE && matches!(ppu.dot, 2..=3) {
+                    // Reading just as it's set sees the flag, but the NMI never happens
+                    ppu.signals.acknowledge_interrupt(InterruptSource::VBLANK_NMI);
+                }
                 if ppu.vblank_started {
                     status |= 0b1000_0000;
                     ppu.vblank_started = false;
@@ -298,38 +411,50 @@ impl PPU {
                 if ppu.sprite_0_hit {
                     status |= 0b0100_0000;
                 }
+                if ppu.sprite_overflow {
+                    status |= 0b0010_0000;
+                }
 
                 // PPU open bus. Returns stale PPU bus contents
-                status |= ppu.data_bus_latch & 0b0001_1111;
+                status |= ppu.read_data_bus() & 0b0001_1111;
 
                 // "Reading any readable port (PPUSTATUS, OAMDATA, or PPUDATA) also fills the latch with the bits read" - https://www.nesdev.org/wiki/PPU_registers#Ports
-                ppu.data_bus_latch = status;
+                ppu.drive_data_bus(status, 0b1110_0000);
                 status
             }
             OAMDATA => {
-                let res = ppu.oam[ppu.oam_addr as usize];
+                // During rendering, this returns whatever sprite evaluation is reading or writing
+                let res = if ppu.is_rendering() {
+                    ppu.oam_data_bus
+                } else {
+                    ppu.access_oam_row(ppu.oam_addr);
+                    ppu.oam[ppu.oam_addr as usize]
+                };
 
                 // "Reading any readable port (PPUSTATUS, OAMDATA, or PPUDATA) also fills the latch with the bits read" - https://www.nesdev.org/wiki/PPU_registers#Ports
-                ppu.data_bus_latch = res;
+                ppu.drive_data_bus(res, 0xFF);
 
                 res
             }
             PPUDATA => {
-                let addr = ppu.v_addr;
-                let res = ppu.read_mem(addr);
-                ppu.v_addr += ppu.control.vram_increment;
-
-                let previous_read = ppu.data_bus_latch;
-                // "Reading any readable port (PPUSTATUS, OAMDATA, or PPUDATA) also fills the latch with the bits read" - https://www.nesdev.org/wiki/PPU_registers#Ports
-                ppu.data_bus_latch = res;
-
-                if addr >= 0x3EFF {
-                    // The palette memory responds immediately
+                let addr = ppu.v_addr & 0x3FFF;
+                increment_v_after_data_access(ppu);
+
+                if addr >= 0x3F00 {
+                    // The palette memory responds immediately, but only drives the low 6 bits.
+                    // The buffer is filled with the nametable byte "underneath" the palette.
+                    ppu.data_read_buffer = ppu.read_mem(addr - 0x1000);
+                    let res = (ppu.read_mem(addr) & 0b0011_1111) | (ppu.read_data_bus() & 0b1100_0000);
+                    ppu.drive_data_bus(res, 0b0011_1111);
                     res
                 } else {
                     // The rest of PPU memory has an intermediate buffer, so we return the data
                     // that was read from memory on the previous PPUDATA read
-                    previous_read
+                    let res = ppu.data_read_buffer;
+                    ppu.data_read_buffer = ppu.read_mem(addr);
+                    // "Reading any readable port (PPUSTATUS, OAMDATA, or PPUDATA) also fills the latch with the bits read" - https://www.nesdev.org/wiki/PPU_registers#Ports
+                    ppu.drive_data_bus(res, 0xFF);
+                    res
                 }
             }
             PPUCTRL |
@@ -338,7 +463,7 @@ impl PPU {
             PPUSCROLL |
             PPUADDR => {
                 // Reading a nominally "write-only" register returns the latch's current value, as do the unused bits of PPUSTATUS. - https://www.nesdev.org/wiki/PPU_registers#Ports
-                ppu.data_bus_latch
+                ppu.read_data_bus()
             }
             _ => unreachable!(),
         }
@@ -348,16 +473,31 @@ impl PPU {
         let ppu = self;
 
         // "Writing any value to any PPU port, even to the nominally read-only PPUSTATUS, will fill this latch" - https://www.nesdev.org/wiki/PPU_registers#Ports
-        ppu.data_bus_latch = val;
+        ppu.drive_data_bus(val, 0xFF);
 
         match addr & 0x2007 {
             PPUCTRL => {
+                let nmi_was_enabled = ppu.control.enable_nmi;
                 ppu.control = PPUControl::from_bits(val);
+                // The NMI output is the vblank flag ANDed with this bit, so enabling NMIs during
+                // vblank causes one straight away. Disabling them as vblank starts cancels the NMI.
+                if ppu.control.enable_nmi && !nmi_was_enabled && ppu.vblank_started {
+                    ppu.signals.request_interrupt(InterruptSource::VBLANK_NMI);
+                } else if !ppu.control.enable_nmi && ppu.scanline == VBLANK_SCANLINE && matches!(ppu.dot, 2..=3) {
+                    ppu.signals.acknowledge_interrupt(InterruptSource::VBLANK_NMI);
+                }
                 let nt_mask = 0b11_00000_00000;
                 ppu.t_addr = (ppu.t_addr & !nt_mask) | (ppu.control.base_nametable_addr & nt_mask);
             }
             PPUMASK => {
-                ppu.mask = PPUMask::from_bits(val);
+                let mask = PPUMask::from_bits(val);
+                if ppu.rendering_enabled() && !mask.show_background_or_sprites {
+                    // OAM was refreshed right up until now
+                    ppu.oam_refresh_dots.fill(ppu.total_dots);
+                } else if !ppu.rendering_enabled() && mask.show_background_or_sprites {
+                    ppu.decay_oam();
+                }
+                ppu.mask = mask;
             }
             PPUSTATUS => {
                 // Do nothing, the only effect of writing PPUSTATUS is that of filling data_bus_latch.
@@ -366,6 +506,7 @@ impl PPU {
                 ppu.oam_addr = val;
             }
             OAMDATA => {
+                ppu.access_oam_row(ppu.oam_addr);
                 ppu.oam[ppu.oam_addr as usize] = val;
                 ppu.oam_addr = ppu.oam_addr.wrapping_add(1);
             }
@@ -381,17 +522,23 @@ impl PPU {
             }
             PPUADDR => {
                 if !ppu.write_toggle_w {
-                    // Write upper byte first
-                    ppu.v_addr = (ppu.v_addr & 0x00FF) | ((val as u16) << 8);
+                    // Write upper byte first. The top bit of t is cleared, since the address is only 14 bits
+                    ppu.t_addr = (ppu.t_addr & 0x00FF) | (((val & 0b0011_1111) as u16) << 8);
                 } else {
-                    // Then lower byte
-                    ppu.v_addr = (ppu.v_addr & 0xFF00) | (val as u16);
+                    // Then lower byte, and t is copied to v
+                    ppu.t_addr = (ppu.t_addr & 0xFF00) | (val as u16);
+                    if ppu.is_rendering() {
+                        // v is updated a few dots later, which matters if the rendering changes it in between
+                        ppu.pending_v_addr = Some((ppu.t_addr, V_ADDR_UPDATE_DELAY));
+                    } else {
+                        ppu.v_addr = ppu.t_addr;
+                    }
                 }
                 ppu.write_toggle_w = !ppu.write_toggle_w;
             }
             PPUDATA => {
                 ppu.write_mem(ppu.v_addr, val);
-                ppu.v_addr += ppu.control.vram_increment;
+                increment_v_after_data_access(ppu);
             }
             _ => unreachable!(),
         }
@@ -402,7 +549,13 @@ impl PPU {
 pub fn do_oam_dma(nes: &mut NES, source_upper_addr: u8) {
     let oam_addr = nes.ppu.oam_addr;
     for i in 0..=255 {
-        nes.ppu.oam[oam_addr.wrapping_add(i) as usize] = nes.read8(((source_upper_addr as u16) << 8) + i as u16);
+        if nes.apu.is_dmc_dma_pending() {
+            nes.do_dmc_dma_during_oam_dma();
+        }
+        nes.tick();
+        let value = nes.read8_no_tick(((source_upper_addr as u16) << 8) + i as u16);
+        nes.ppu.access_oam_row(oam_addr.wrapping_add(i));
+        nes.ppu.oam[oam_addr.wrapping_add(i) as usize] = value;
         nes.tick(); // PPU write cycle
     }
     nes.tick(); // 1 wait-state while waiting for writes to complete
@@ -412,6 +565,9 @@ pub fn do_oam_dma(nes: &mut NES, source_upper_addr: u8) {
 }
 
 const FIRST_SCANLINE: u32 = 0;
+const VBLANK_SCANLINE: u32 = 241;
+/// How many dots after a PPUADDR write the new address reaches v
+const V_ADDR_UPDATE_DELAY: u8 = 3;
 const LAST_SCANLINE: u32 = 261;
 const DOTS_PER_SCANLINE: u32 = 341;
 
@@ -424,12 +580,13 @@ impl PPU {
             0..=239 => {
                 do_scanline_rendering(ppu);
             }
-            241 => {
+            VBLANK_SCANLINE => {
                 if ppu.dot == 1 {
                     // A full frame has been rendered, make it visible
                     ppu.flip_frame();
-                    ppu.vblank_started = true;
-                    if ppu.control.enable_nmi {
+                    ppu.vblank_started = !ppu.suppress_vblank;
+                    ppu.suppress_vblank = false;
+                    if ppu.vblank_started && ppu.control.enable_nmi {
                         ppu.signals.request_interrupt(InterruptSource::VBLANK_NMI);
                     }
                 }
@@ -439,6 +596,7 @@ impl PPU {
                 if ppu.dot == 1 {
                     ppu.vblank_started = false;
                     ppu.sprite_0_hit = false;
+                    ppu.sprite_overflow = false;
                 }
                 do_scanline_rendering(ppu);
 
@@ -450,7 +608,15 @@ impl PPU {
             _ => {}
         }
 
+        update_v_from_pending(ppu);
+
+        ppu.total_dots += 1;
         ppu.dot += 1;
+        // With rendering enabled, the last dot of the pre-render line is skipped on odd frames,
+        // which keeps the NTSC dot crawl from flickering. https://www.nesdev.org/wiki/PPU_frame_timing#Even/Odd_Frames
+        if ppu.scanline == LAST_SCANLINE && ppu.dot == DOTS_PER_SCANLINE - 1 && ppu.frame_num % 2 == 1 && ppu.rendering_enabled() {
+            ppu.dot = DOTS_PER_SCANLINE;
+        }
         if ppu.dot >= DOTS_PER_SCANLINE {
             ppu.dot = 0;
             ppu.scanline += 1;
@@ -465,33 +631,52 @@ impl PPU {
 fn do_scanline_rendering(ppu: &mut PPU) {
     let dot = ppu.dot;
 
+    // Sprite evaluation for the next line happens alongside background rendering, but not on the pre-render line.
+    // Since sprite evaluation doesn't occur on the pre-render scanline, no sprites can appear on line 0, so the
+    // sprite Y values are offset by one. At line 0 we evaluate sprites for Y=0 then display them on line 1.
+    if ppu.rendering_enabled() {
+        match dot {
+            1..=256 if ppu.scanline != LAST_SCANLINE => sprite_eval::step_sprite_evaluation(ppu),
+            0 | 321..=340 => sprite_eval::step_sprite_idle(ppu),
+            _ => {}
+        }
+    }
+
     // See the cycles here https://www.nesdev.org/wiki/PPU_rendering#Visible_scanlines_(0-239)
     match dot {
         0..=256 | 321..=336 => {
             // Background fetches - https://www.nesdev.org/wiki/File:Ppu.svg
-            if dot > 0 && dot % 8 == 0 {
-                // Cycles 1 & 2
-                let tile_addr = 0x2000 | (ppu.v_addr & 0x0FFF);
-                let tile_index: u8 = ppu.mapper.read_nametable(tile_addr);
-
-                // Cycles 3 & 4
-                let palette_index: u8 = read_next_palette_index(ppu);
-
-                // Cycles 5 & 6
-                let fine_y: u16 = ppu.v_addr >> 12 & 0b111;
-                let pattern_addr = ppu.control.background_pattern_table + (tile_index as u16) * 16 + fine_y;
-                let next_tile_lo: u8 = ppu.mapper.read_pattern_table(pattern_addr);
-
-                // Cycles 7 & 0
-                let next_tile_hi: u8 = ppu.mapper.read_pattern_table(pattern_addr + 8);
-
-                ppu.tiles_palette_lo = (ppu.tiles_palette_lo & 0xFF00) | if palette_index & 1 != 0 { 0x00FF } else { 0x0000 };
-                ppu.tiles_palette_hi = (ppu.tiles_palette_hi & 0xFF00) | if palette_index & 2 != 0 { 0x00FF } else { 0x0000 };
-                ppu.tiles_lo = (ppu.tiles_lo & 0xFF00) | next_tile_lo as u16;
-                ppu.tiles_hi = (ppu.tiles_hi & 0xFF00) | next_tile_hi as u16;
-
-                if ppu.rendering_enabled() {
-                    scroll_next_x(ppu);
+            // Each memory access takes two dots, we do them on the second one.
+            if dot > 0 {
+                match dot % 8 {
+                    2 if ppu.rendering_enabled() => {
+                        let tile_addr = 0x2000 | (ppu.v_addr & 0x0FFF);
+                        ppu.next_tile_index = ppu.mapper.read_nametable(tile_addr);
+                    }
+                    4 if ppu.rendering_enabled() => {
+                        ppu.next_tile_palette = read_next_palette_index(ppu);
+                    }
+                    6 if ppu.rendering_enabled() => {
+                        let pattern_addr = background_pattern_addr(ppu);
+                        ppu.next_tile_lo = ppu.mapper.read_pattern_table(pattern_addr);
+                    }
+                    0 => {
+                        if ppu.rendering_enabled() {
+                            let pattern_addr = background_pattern_addr(ppu);
+                            ppu.next_tile_hi = ppu.mapper.read_pattern_table(pattern_addr + 8);
+                        }
+
+                        let palette_index = ppu.next_tile_palette;
+                        ppu.tiles_palette_lo = (ppu.tiles_palette_lo & 0xFF00) | if palette_index & 1 != 0 { 0x00FF } else { 0x0000 };
+                        ppu.tiles_palette_hi = (ppu.tiles_palette_hi & 0xFF00) | if palette_index & 2 != 0 { 0x00FF } else { 0x0000 };
+                        ppu.tiles_lo = (ppu.tiles_lo & 0xFF00) | ppu.next_tile_lo as u16;
+                        ppu.tiles_hi = (ppu.tiles_hi & 0xFF00) | ppu.next_tile_hi as u16;
+
+                        if ppu.rendering_enabled() {
+                            scroll_next_x(ppu);
+                        }
+                    }
+                    _ => {}
                 }
             }
 
@@ -509,22 +694,23 @@ fn do_scanline_rendering(ppu: &mut PPU) {
                 ppu.tiles_palette_hi <<= 1;
             }
         }
+        // Two unused nametable fetches, which MMC5 uses to detect the end of a scanline
+        338 | 340 if ppu.rendering_enabled() => {
+            let tile_addr = 0x2000 | (ppu.v_addr & 0x0FFF);
+            ppu.next_tile_index = ppu.mapper.read_nametable(tile_addr);
+        }
         // Sprite-loading interval
         257..=320 => {
             if ppu.rendering_enabled() {
-                ppu.oam_addr = 0;
-
-                // We totally ignore the real cycles here, & just do all evaluation in one cycle.
-                // I don't think there's an observable difference between this and the real thing.
-                // https://www.nesdev.org/wiki/PPU_sprite_evaluation
-                if dot == 257 && ppu.scanline != 261 { // Sprite evaluation doesn't happen on the pre-render scanline
-                    // We're evaluating the sprites for the next line, but since sprite evaluation
-                    // doesn't occur on the pre-render scanline, no sprites can appear on line 0,
-                    // so the sprite Y values are offset by one. This means at the end of line 0 we
-                    // evaluate sprites for Y=0 then display them on line 1.
-                    let line = ppu.scanline;
-                    evaluate_sprites_for_line(ppu, line);
+                // If OAMADDR was left pointing past the first two sprites when rendering starts,
+                // the 8 bytes around it are copied over them. https://www.nesdev.org/wiki/PPU_registers#OAMADDR
+                if dot == 257 && ppu.scanline == LAST_SCANLINE && ppu.oam_addr >= 8
+                    && ppu.accuracy.contains(PPUAccuracy::OAM_ADDR_CORRUPTION) {
+                    let row = (ppu.oam_addr & 0xF8) as usize;
+                    ppu.oam.copy_within(row..row + 8, 0);
                 }
+                ppu.oam_addr = 0;
+                sprite_eval::step_sprite_fetches(ppu);
             }
         }
         _ => {}
@@ -555,6 +741,42 @@ fn update_y_from_temp(ppu: &mut PPU) {
 
 /// https://www.nesdev.org/wiki/PPU_scrolling#Between_dot_328_of_a_scanline,_and_256_of_the_next_scanline
 /// https://www.nesdev.org/wiki/PPU_scrolling#Coarse_X_increment
+/// Outside of rendering, PPUDATA accesses increment v by 1 or 32. During rendering, the PPU
+/// increments the coarse X and Y scroll instead, both at once.
+/// https://www.nesdev.org/wiki/PPU_scrolling#$2007_(PPUDATA)_reads_and_writes
+fn increment_v_after_data_access(ppu: &mut PPU) {
+    if ppu.is_rendering() {
+        scroll_next_x(ppu);
+        scroll_next_y(ppu);
+    } else {
+        ppu.v_addr = ppu.v_addr.wrapping_add(ppu.control.vram_increment) & 0x7FFF;
+    }
+}
+
+/// Applies a PPUADDR write to v, once its delay is up.
+fn update_v_from_pending(ppu: &mut PPU) {
+    let Some((addr, dots_left)) = ppu.pending_v_addr else { return; };
+    if dots_left > 1 {
+        ppu.pending_v_addr = Some((addr, dots_left - 1));
+        return;
+    }
+    ppu.pending_v_addr = None;
+
+    let dot = ppu.dot;
+    if !ppu.is_rendering() {
+        ppu.v_addr = addr;
+    } else if dot == 257 {
+        // Landing on the copy of the horizontal scroll from t, the two get ANDed together
+        ppu.v_addr &= addr;
+    } else if dot > 0 && dot & 7 == 0 && (dot <= 256 || dot > 320) {
+        // Landing on a coarse X (or Y) increment, the incremented scroll bits get ANDed with the written ones
+        let scroll_bits = 0x041F;
+        ppu.v_addr = (addr & !scroll_bits) | (ppu.v_addr & addr & scroll_bits);
+    } else {
+        ppu.v_addr = addr;
+    }
+}
+
 fn scroll_next_x(ppu: &mut PPU) {
     if ppu.v_addr & 0x001F == 31 { // Coarse X == 31
         ppu.v_addr = (ppu.v_addr & !0x001F) // coarse X = 0
@@ -629,7 +851,13 @@ fn render_pixel(ppu: &mut PPU, x: u32) {
         }
     }
 
-    ppu.cur_display_buffer[(ppu.scanline * 256 + x) as usize] = ppu.palettes[pixel_index as usize] & ppu.mask.grayscale_mask;
+    let color = ppu.palettes[pixel_index as usize] & ppu.mask.grayscale_mask;
+    ppu.cur_display_buffer[(ppu.scanline * 256 + x) as usize] = color as u16 | ppu.mask.emphasis_bits;
+}
+
+fn background_pattern_addr(ppu: &PPU) -> u16 {
+    let fine_y: u16 = ppu.v_addr >> 12 & 0b111;
+    ppu.control.background_pattern_table + (ppu.next_tile_index as u16) * 16 + fine_y
 }
 
 fn read_next_palette_index(ppu: &mut PPU) -> u8 {
@@ -685,75 +913,6 @@ const SPRITE_ATTR_PALETTE: u8 = 0b0000_0011;
 const SPRITE_ATTR_FLIP_H: u8 = 0b0100_0000;
 const SPRITE_ATTR_FLIP_V: u8 = 0b1000_0000;
 
-fn evaluate_sprites_for_line(ppu: &mut PPU, line: u32) {
-    let mut dest_index = 0usize;
-    let sprite_size = ppu.control.sprite_size;
-    let sprite_height = sprite_size.height();
-    for src_index in 0..NUM_SPRITES {
-        let sprite_data: [u8; 4] = ppu.oam[src_index * 4 .. (src_index + 1) * 4].try_into().unwrap();
-        let y = sprite_data[SPRITE_Y] as u32;
-        let y_range = y..y + sprite_height;
-        if !y_range.contains(&line) {
-            continue;
-        }
-
-        let attrs = sprite_data[SPRITE_ATTRIBUTES];
-        let tile_index = sprite_data[SPRITE_TILE_INDEX];
-        let pattern_addr: u16;
-        match sprite_size {
-            SpriteSize::Size8x8 => {
-                let mut y_offset = line - y;
-                if attrs & SPRITE_ATTR_FLIP_V != 0 {
-                    y_offset = 7 - y_offset;
-                }
-                pattern_addr = ppu.control.sprite_pattern_table + (tile_index as u16) * 16 + (y_offset as u16);
-            }
-            SpriteSize::Size8x16 => {
-                let mut y_offset = line - y;
-                let pattern_table = if tile_index & 1 == 1 { 0x1000 } else { 0x0000 };
-                let mut tile_index = tile_index & !1;
-                if attrs & SPRITE_ATTR_FLIP_V != 0 {
-                    if y_offset >= 8 {
-                        y_offset -= 8;
-                    } else {
-                        tile_index += 1;
-                    }
-                    y_offset = 7 - y_offset;
-                } else {
-                    if y_offset >= 8 {
-                        tile_index += 1;
-                        y_offset -= 8;
-                    }
-                }
-                pattern_addr = pattern_table + (tile_index as u16) * 16 + (y_offset as u16);
-            }
-        };
-
-        let mut pat_lower = ppu.mapper.read_pattern_table(pattern_addr);
-        let mut pat_upper = ppu.mapper.read_pattern_table(pattern_addr + 8);
-        if attrs & SPRITE_ATTR_FLIP_H == 0 {
-            pat_lower = pat_lower.reverse_bits();
-            pat_upper = pat_upper.reverse_bits();
-        }
-        let pattern2 = interleave_bits(pat_lower, pat_upper);
-
-        let start_x: u8 = sprite_data[SPRITE_X];
-        ppu.cur_line_sprites[dest_index] = SpriteRowSlice {
-            start_x,
-            end_x: start_x as u16 + 8,
-            pattern2,
-            above_bg: (attrs & SPRITE_ATTR_BEHIND_BG) == 0,
-            palette_base_addr: 0x10 | ((attrs & SPRITE_ATTR_PALETTE) << 2),
-            is_sprite_0: src_index == 0,
-        };
-        dest_index += 1;
-        if dest_index >= ppu.cur_line_sprites.len() {
-            break;
-        }
-    }
-    ppu.cur_line_num_sprites = dest_index;
-}
-
 /// Interleaves bits like so:
 /// interleave_bits(0b00, 0b11) == 0b1010
 /// interleave_bits(0b11, 0b00) == 0b0101
@@ -786,3 +945,280 @@ fn test_interleave_bits() {
         }
     }
 }
+
+#[cfg(test)]
+fn new_test_ppu() -> PPU {
+    use crate::cartridge::{Cartridge, CHR};
+    use crate::mapper::MapperDescriptor;
+
+    let cart = Cartridge::test_cart(MapperDescriptor::NROM, vec![0; 0x8000], CHR::ROM(vec![0xFF; 0x2000].into_boxed_slice()));
+    let signals = Signals::new();
+    let mapper = Rc::new(Mapper::new(cart, Rc::clone(&signals)));
+    let mut ppu = PPU::new(mapper, signals);
+    ppu.write_register(PPUMASK, 0b0001_1000);
+    ppu
+}
+
+#[test]
+fn test_odd_frame_skipped_dot() {
+    fn frame_dots(ppu: &mut PPU) -> u32 {
+        let frame = ppu.frame_num;
+        let mut dots = 0;
+        while ppu.frame_num == frame {
+            ppu.step_cycle();
+            dots += 1;
+        }
+        dots
+    }
+
+    let mut ppu = new_test_ppu();
+    assert_eq!(frame_dots(&mut ppu), 341 * 262);
+    assert_eq!(frame_dots(&mut ppu), 341 * 262 - 1);
+    assert_eq!(frame_dots(&mut ppu), 341 * 262);
+
+    // No dot is skipped with rendering disabled
+    ppu.write_register(PPUMASK, 0);
+    assert_eq!(frame_dots(&mut ppu), 341 * 262);
+}
+
+#[test]
+fn test_fetch_timing() {
+    let mut ppu = new_test_ppu();
+    // Sprites from $1000, so their fetches stand out from the background's
+    ppu.write_register(PPUCTRL, 0b0000_1000);
+    // Each tile index in the first two rows is its column
+    for nametable in [0x2000, 0x2400] {
+        for addr in 0..64 {
+            ppu.mapper.write_nametable(nametable + addr, addr as u8 % 32);
+        }
+    }
+    // Eight sprites, tiles $10-$17, showing their last row on line 11
+    for i in 0..8 {
+        ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[3, 0x10 + i as u8, 0, i as u8 * 8]);
+    }
+
+    while ppu.scanline != 10 || ppu.dot != 0 {
+        ppu.step_cycle();
+    }
+    ppu.mapper.take_ppu_reads();
+    let mut reads = Vec::new();
+    while ppu.scanline == 10 {
+        let dot = ppu.dot;
+        ppu.step_cycle();
+        reads.extend(ppu.mapper.take_ppu_reads().into_iter().map(|addr| (dot, addr)));
+    }
+
+    // Line 10 is row 1 of the tiles, fine Y 2, and line 11 is fine Y 3
+    let tile_fetches = |first_dot: u32, column: u16, fine_y: u16| {
+        let nametable = 0x2000 | (column / 32) << 10;
+        let tile = column % 32;
+        [
+            (first_dot + 1, nametable | 32 | tile),
+            (first_dot + 3, nametable | 0x3C0 | tile / 4),
+            (first_dot + 5, tile * 16 + fine_y),
+            (first_dot + 7, tile * 16 + fine_y + 8),
+        ]
+    };
+    let mut expected = Vec::new();
+    // The visible dots fetch tiles 2 to 33, the first two were fetched on the line before
+    for i in 0..32 {
+        expected.extend(tile_fetches(1 + i * 8, i as u16 + 2, 2));
+    }
+    // Two garbage nametable fetches then the pattern fetches for each sprite's last row
+    for i in 0..8 {
+        let tile = 0x10 + i as u16;
+        expected.extend([(258 + i * 8, 0x2020), (260 + i * 8, 0x2020), (262 + i * 8, 0x1000 + tile * 16 + 7), (264 + i * 8, 0x1000 + tile * 16 + 15)]);
+    }
+    // The first two tiles of the next line, then two unused nametable fetches
+    expected.extend(tile_fetches(321, 0, 3));
+    expected.extend(tile_fetches(329, 1, 3));
+    expected.extend([(338, 0x2022), (340, 0x2022)]);
+
+    assert_eq!(reads, expected);
+}
+
+#[test]
+fn test_vblank_nmi_races() {
+    fn run_to(ppu: &mut PPU, scanline: u32, dot: u32) {
+        while ppu.scanline != scanline || ppu.dot != dot {
+            ppu.step_cycle();
+        }
+    }
+
+    let mut ppu = new_test_ppu();
+    ppu.write_register(PPUCTRL, 0b1000_0000);
+
+    // A normal vblank
+    run_to(&mut ppu, VBLANK_SCANLINE, 5);
+    assert!(ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+    assert_ne!(ppu.read_register(PPUSTATUS) & 0x80, 0);
+    ppu.signals.acknowledge_interrupt(InterruptSource::VBLANK_NMI);
+
+    // Reading PPUSTATUS on the dot before vblank starts suppresses the flag and NMI
+    run_to(&mut ppu, VBLANK_SCANLINE, 1);
+    assert_eq!(ppu.read_register(PPUSTATUS) & 0x80, 0);
+    run_to(&mut ppu, VBLANK_SCANLINE, 5);
+    assert!(!ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+    assert_eq!(ppu.read_register(PPUSTATUS) & 0x80, 0);
+
+    // Reading it as vblank starts returns the flag but suppresses the NMI
+    run_to(&mut ppu, VBLANK_SCANLINE, 2);
+    assert_ne!(ppu.read_register(PPUSTATUS) & 0x80, 0);
+    assert!(!ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+
+    // Toggling NMIs on during vblank causes an NMI each time
+    ppu.write_register(PPUCTRL, 0);
+    run_to(&mut ppu, VBLANK_SCANLINE + 1, 0);
+    run_to(&mut ppu, VBLANK_SCANLINE, 100);
+    assert!(!ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+    ppu.write_register(PPUCTRL, 0b1000_0000);
+    assert!(ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+    ppu.signals.acknowledge_interrupt(InterruptSource::VBLANK_NMI);
+    ppu.write_register(PPUCTRL, 0b1000_0000);
+    assert!(!ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+    ppu.write_register(PPUCTRL, 0);
+    ppu.write_register(PPUCTRL, 0b1000_0000);
+    assert!(ppu.signals.is_active(InterruptSource::VBLANK_NMI));
+}
+
+#[test]
+fn test_vblank_edge_timing() {
+    // A PPU on the given dot of line 241, with the vblank flag clear, no NMI pending and NMIs enabled as asked
+    fn ppu_at_vblank_dot(dot: u32, enable_nmi: bool) -> PPU {
+        let mut ppu = new_test_ppu();
+        ppu.write_register(PPUCTRL, if enable_nmi { 0b1000_0000 } else { 0 });
+        while ppu.scanline != LAST_SCANLINE || ppu.dot != 2 {
+            ppu.step_cycle();
+        }
+        ppu.signals.acknowledge_interrupt(InterruptSource::VBLANK_NMI);
+        while ppu.scanline != VBLANK_SCANLINE || ppu.dot != dot {
+            ppu.step_cycle();
+        }
+        ppu
+    }
+    fn run_past_edge(ppu: &mut PPU) {
+        while ppu.dot < 10 {
+            ppu.step_cycle();
+        }
+    }
+
+    // PPUSTATUS reads: (dot, flag read, NMI happens, flag still set afterwards)
+    for (dot, flag, nmi, flag_after) in [
+        (0, false, true, true),
+        (1, false, false, false),
+        (2, true, false, false),
+        (3, true, false, false),
+        (4, true, true, false),
+    ] {
+        let mut ppu = ppu_at_vblank_dot(dot, true);
+        assert_eq!(ppu.read_register(PPUSTATUS) & 0x80 != 0, flag, "PPUSTATUS read on dot {}", dot);
+        run_past_edge(&mut ppu);
+        assert_eq!(ppu.signals.is_active(InterruptSource::VBLANK_NMI), nmi, "PPUSTATUS read on dot {}", dot);
+        assert_eq!(ppu.read_register(PPUSTATUS) & 0x80 != 0, flag_after, "PPUSTATUS read on dot {}", dot);
+    }
+
+    // Disabling NMIs: (dot, NMI happens)
+    for (dot, nmi) in [(0, false), (1, false), (2, false), (3, false), (4, true)] {
+        let mut ppu = ppu_at_vblank_dot(dot, true);
+        ppu.write_register(PPUCTRL, 0);
+        run_past_edge(&mut ppu);
+        assert_eq!(ppu.signals.is_active(InterruptSource::VBLANK_NMI), nmi, "NMI disabled on dot {}", dot);
+    }
+
+    // Enabling NMIs always causes one, either as the flag is set or straight away
+    for dot in 0..=4 {
+        let mut ppu = ppu_at_vblank_dot(dot, false);
+        ppu.write_register(PPUCTRL, 0b1000_0000);
+        assert_eq!(ppu.signals.is_active(InterruptSource::VBLANK_NMI), dot >= 2, "NMI enabled on dot {}", dot);
+        run_past_edge(&mut ppu);
+        assert!(ppu.signals.is_active(InterruptSource::VBLANK_NMI), "NMI enabled on dot {}", dot);
+    }
+}
+
+#[test]
+fn test_open_bus_decay() {
+    let mut ppu = new_test_ppu();
+    ppu.write_register(PPUMASK, 0);
+    ppu.set_accuracy(PPUAccuracy::OPEN_BUS_DECAY);
+
+    ppu.write_register(PPUADDR, 0x3F);
+    ppu.write_register(PPUADDR, 0x00);
+    ppu.write_register(PPUDATA, 0x3F);
+    ppu.write_register(PPUADDR, 0x3F);
+    ppu.write_register(PPUADDR, 0x00);
+    ppu.write_register(PPUSTATUS, 0xFF);
+    for _ in 0..OPEN_BUS_DECAY_DOTS / 2 {
+        ppu.step_cycle();
+    }
+
+    // Palette reads only drive the low 6 bits, the top 2 come from the latch
+    assert_eq!(ppu.read_register(PPUDATA), 0xFF);
+    for _ in 0..OPEN_BUS_DECAY_DOTS / 2 + 10 {
+        ppu.step_cycle();
+    }
+    assert_eq!(ppu.read_register(OAMADDR), 0x3F);
+    for _ in 0..OPEN_BUS_DECAY_DOTS {
+        ppu.step_cycle();
+    }
+    assert_eq!(ppu.read_register(OAMADDR), 0x00);
+}
+
+#[test]
+fn test_oam_decay_and_corruption() {
+    let mut ppu = new_test_ppu();
+    ppu.set_accuracy(PPUAccuracy::OAM_DECAY | PPUAccuracy::OAM_ADDR_CORRUPTION);
+    for i in 0..=255 {
+        ppu.oam[i] = i as u8;
+    }
+
+    // Leaving OAMADDR at $23 when rendering starts copies $20-$27 over sprites 0 and 1
+    ppu.write_register(OAMADDR, 0x23);
+    ppu.scanline = LAST_SCANLINE;
+    ppu.dot = 0;
+    while ppu.scanline == LAST_SCANLINE {
+        ppu.step_cycle();
+    }
+    assert_eq!(ppu.oam[0..8], [0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27]);
+
+    // With rendering off, OAM rows that aren't accessed decay
+    ppu.write_register(PPUMASK, 0);
+    for _ in 0..OAM_DECAY_DOTS / 2 {
+        ppu.step_cycle();
+    }
+    ppu.write_register(OAMADDR, 0x40);
+    ppu.read_register(OAMDATA);
+    for _ in 0..OAM_DECAY_DOTS / 2 + 10 {
+        ppu.step_cycle();
+    }
+    ppu.write_register(PPUMASK, 0b0001_1000);
+    assert_eq!(ppu.oam[0x40], 0x40);
+    assert_eq!(ppu.oam[0x80], OAM_DECAYED_VALUE);
+}
+
+#[test]
+fn test_data_access_during_rendering() {
+    let mut ppu = new_test_ppu();
+    ppu.write_register(PPUMASK, 0);
+
+    // Outside of rendering, PPUADDR sets v straight away and PPUDATA increments it
+    ppu.write_register(PPUADDR, 0x21);
+    ppu.write_register(PPUADDR, 0x05);
+    ppu.read_register(PPUDATA);
+    assert_eq!(ppu.v_addr, 0x2106);
+
+    // During rendering, the PPUADDR write reaches v a few dots later
+    ppu.write_register(PPUMASK, 0b0001_1000);
+    ppu.scanline = 100;
+    ppu.dot = 2;
+    ppu.write_register(PPUADDR, 0x01);
+    ppu.write_register(PPUADDR, 0x05);
+    assert_ne!(ppu.v_addr, 0x0105);
+    for _ in 0..V_ADDR_UPDATE_DELAY {
+        ppu.step_cycle();
+    }
+    assert_eq!(ppu.v_addr, 0x0105);
+
+    // And PPUDATA increments coarse X and Y at once
+    ppu.read_register(PPUDATA);
+    assert_eq!(ppu.v_addr, 0x1106);
+}
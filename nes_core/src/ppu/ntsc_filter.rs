@@ -0,0 +1,251 @@
+use crate::ppu::{Color, Palette, PALETTE_COLORS, SCREEN_HEIGHT, SCREEN_PIXELS, SCREEN_WIDTH};
+use crate::ppu::ntsc_palette::{decoder_angle, NtscPaletteParams, PHASES, signal_level, yiq_to_color};
+
+/// The filtered image has two pixels for every PPU pixel, enough to show the artifacts.
+pub const NTSC_OUTPUT_WIDTH: u32 = SCREEN_WIDTH * 2;
+pub const NTSC_OUTPUT_PIXELS: usize = (NTSC_OUTPUT_WIDTH * SCREEN_HEIGHT) as usize;
+
+/// The PPU outputs 8 samples of the signal for each pixel, at 12 samples per color cycle.
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_LINE: usize = SCREEN_WIDTH as usize * SAMPLES_PER_PIXEL;
+const SAMPLES_PER_OUTPUT_PIXEL: usize = SAMPLES_PER_LINE / NTSC_OUTPUT_WIDTH as usize;
+
+/// A line is 341 dots of 8 samples, so each line starts 4 phases (a third of a color cycle) after
+/// the last. This makes the diagonal "jaggies" along the edges of colors.
+const LINE_PHASE_STEP: usize = 341 * SAMPLES_PER_PIXEL % PHASES;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NtscFilterPreset {
+    /// Luma and chroma share one signal, so fine patterns of luma are decoded as color, and
+    /// colors bleed into their neighbours.
+    Composite,
+    /// Luma and chroma are carried separately, so they don't interfere, but colors still blur.
+    SVideo,
+    /// No filtering, the palette's colors are output directly.
+    Rgb,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NtscFilterSettings {
+    /// Separate luma and chroma signals (S-Video), rather than one composite signal
+    pub separate_chroma: bool,
+    /// How many samples the luma is averaged over. 12 (one color cycle) filters the chroma out of
+    /// a composite signal, smaller values are sharper. Clamped to between 1 and a whole line.
+    pub luma_width: usize,
+    /// How many samples the chroma is averaged over. Larger values make colors bleed further.
+    /// Clamped like `luma_width`.
+    pub chroma_width: usize,
+    pub params: NtscPaletteParams,
+}
+
+impl NtscFilterPreset {
+    /// The settings for the preset, or None for RGB, which isn't filtered.
+    pub fn settings(self) -> Option<NtscFilterSettings> {
+        match self {
+            NtscFilterPreset::Composite => Some(NtscFilterSettings {
+                separate_chroma: false,
+                luma_width: 12,
+                chroma_width: 24,
+                params: NtscPaletteParams::default(),
+            }),
+            NtscFilterPreset::SVideo => Some(NtscFilterSettings {
+                separate_chroma: true,
+                luma_width: 4,
+                chroma_width: 12,
+                params: NtscPaletteParams::default(),
+            }),
+            NtscFilterPreset::Rgb => None,
+        }
+    }
+}
+
+/// Simulates the PPU's NTSC video signal and decodes it like a TV, to get the dot crawl, color
+/// bleeding and artifact colors a real console shows. This all runs on the CPU.
+/// https://www.nesdev.org/wiki/NTSC_video
+pub struct NtscFilter {
+    /// None for the RGB preset
+    settings: Option<NtscFilterSettings>,
+    /// The signal for every color at each phase of the color cycle
+    signal_table: Box<[[f32; PHASES]; PALETTE_COLORS]>,
+    /// The average signal for every color, used as its luma signal for S-Video
+    luma_table: Box<[f32; PALETTE_COLORS]>,
+    carrier_cos: [f32; PHASES],
+    carrier_sin: [f32; PHASES],
+
+    // Running sums of one line's signals, reused between lines
+    luma_sums: Vec<f32>,
+    i_sums: Vec<f32>,
+    q_sums: Vec<f32>,
+}
+
+impl NtscFilter {
+    pub fn new(preset: NtscFilterPreset) -> NtscFilter {
+        NtscFilter::with_settings(preset.settings())
+    }
+
+    pub fn with_settings(settings: Option<NtscFilterSettings>) -> NtscFilter {
+        let settings = settings.map(|settings| NtscFilterSettings {
+            luma_width: settings.luma_width.clamp(1, SAMPLES_PER_LINE),
+            chroma_width: settings.chroma_width.clamp(1, SAMPLES_PER_LINE),
+            ..settings
+        });
+
+        let mut signal_table = Box::new([[0.0; PHASES]; PALETTE_COLORS]);
+        let mut luma_table = Box::new([0.0; PALETTE_COLORS]);
+        for (index, signals) in signal_table.iter_mut().enumerate() {
+            for (phase, signal) in signals.iter_mut().enumerate() {
+                *signal = signal_level(index, phase);
+            }
+            luma_table[index] = signals.iter().sum::<f32>() / PHASES as f32;
+        }
+
+        let params = settings.map(|s| s.params).unwrap_or_default();
+        let mut carrier_cos = [0.0; PHASES];
+        let mut carrier_sin = [0.0; PHASES];
+        for phase in 0..PHASES {
+            let angle = decoder_angle(phase, &params);
+            carrier_cos[phase] = angle.cos();
+            carrier_sin[phase] = angle.sin();
+        }
+
+        NtscFilter {
+            settings,
+            signal_table,
+            luma_table,
+            carrier_cos,
+            carrier_sin,
+            luma_sums: vec![0.0; SAMPLES_PER_LINE + 1],
+            i_sums: vec![0.0; SAMPLES_PER_LINE + 1],
+            q_sums: vec![0.0; SAMPLES_PER_LINE + 1],
+        }
+    }
+
+    /// Filters a frame of 9-bit palette indices into `NTSC_OUTPUT_WIDTH` x `SCREEN_HEIGHT` pixels of
+    /// RGB (in the format 0x00RRGGBB). The palette is only used for the RGB preset.
+    /// The phase of the color cycle moves each frame, so `frame_num` is needed for the dot crawl.
+    pub fn apply(&mut self, indices: &[u16; SCREEN_PIXELS], frame_num: u64, palette: &Palette, output: &mut [u32; NTSC_OUTPUT_PIXELS]) {
+        let to_argb = |Color { r, g, b }: Color| (r as u32) << 16 | (g as u32) << 8 | (b as u32);
+
+        let Some(settings) = self.settings else {
+            for (i, palette_index) in indices.iter().enumerate() {
+                let argb = to_argb(palette.color(*palette_index));
+                output[i * 2] = argb;
+                output[i * 2 + 1] = argb;
+            }
+            return;
+        };
+
+        // With rendering on, frames are alternately 4 and 8 phases long (with the skipped dot),
+        // so alternate frames start on different phases.
+        let frame_phase = (frame_num % 2) as usize * LINE_PHASE_STEP;
+        let width = SCREEN_WIDTH as usize;
+        for y in 0..SCREEN_HEIGHT as usize {
+            let line_phase = (frame_phase + y * LINE_PHASE_STEP) % PHASES;
+            let line = &indices[y * width..(y + 1) * width];
+            self.encode_line(&settings, line, line_phase);
+
+            let output_line = &mut output[y * NTSC_OUTPUT_WIDTH as usize..(y + 1) * NTSC_OUTPUT_WIDTH as usize];
+            for (x, pixel) in output_line.iter_mut().enumerate() {
+                let center = x * SAMPLES_PER_OUTPUT_PIXEL + SAMPLES_PER_OUTPUT_PIXEL / 2;
+                let luma = window_average(&self.luma_sums, center, settings.luma_width);
+                let i = window_average(&self.i_sums, center, settings.chroma_width);
+                let q = window_average(&self.q_sums, center, settings.chroma_width);
+                *pixel = to_argb(yiq_to_color(luma, i, q, &settings.params));
+            }
+        }
+    }
+
+    /// Generates the signal for a line, and fills the running sums of its luma and demodulated chroma.
+    fn encode_line(&mut self, settings: &NtscFilterSettings, line: &[u16], line_phase: usize) {
+        let (mut luma_sum, mut i_sum, mut q_sum) = (0.0f32, 0.0f32, 0.0f32);
+        for (x, palette_index) in line.iter().enumerate() {
+            let index = *palette_index as usize % PALETTE_COLORS;
+            for sample in 0..SAMPLES_PER_PIXEL {
+                let k = x * SAMPLES_PER_PIXEL + sample;
+                let phase = (k + line_phase) % PHASES;
+                let signal = self.signal_table[index][phase];
+
+                // In a composite signal the decoder can't tell luma and chroma apart, so the
+                // luma and chroma filters both see the whole signal.
+                let (luma, chroma) = if settings.separate_chroma {
+                    let luma = self.luma_table[index];
+                    (luma, signal - luma)
+                } else {
+                    (signal, signal)
+                };
+
+                luma_sum += luma;
+                i_sum += chroma * self.carrier_cos[phase];
+                q_sum += chroma * self.carrier_sin[phase];
+                self.luma_sums[k + 1] = luma_sum;
+                self.i_sums[k + 1] = i_sum;
+                self.q_sums[k + 1] = q_sum;
+            }
+        }
+    }
+}
+
+/// The average of `width` samples around `center`, from running sums of the samples.
+fn window_average(sums: &[f32], center: usize, width: usize) -> f32 {
+    let num_samples = sums.len() - 1;
+    let start = center.saturating_sub(width / 2).min(num_samples - width);
+    let end = start + width;
+    (sums[end] - sums[start]) / width as f32
+}
+
+#[test]
+fn test_ntsc_filter() {
+    use crate::ppu::generate_ntsc_palette;
+
+    let palette = generate_ntsc_palette(&NtscPaletteParams::default());
+    let mut output = Box::new([0u32; NTSC_OUTPUT_PIXELS]);
+
+    // A flat color decodes to the same color as the generated palette
+    let indices = Box::new([0x16u16; SCREEN_PIXELS]);
+    NtscFilter::new(NtscFilterPreset::Composite).apply(&indices, 0, &palette, &mut output);
+    let Color { r, g, b } = palette.color(0x16);
+    let expected = (r as u32) << 16 | (g as u32) << 8 | (b as u32);
+    let channel_diff = |a: u32, b: u32, shift: u32| ((a >> shift & 0xFF) as i32 - (b >> shift & 0xFF) as i32).abs();
+    let center = output[100 * NTSC_OUTPUT_WIDTH as usize + 256];
+    for shift in [0, 8, 16] {
+        assert!(channel_diff(center, expected, shift) <= 2);
+    }
+
+    // Alternating white and black pixels make artifact colors on a composite signal, but not on S-Video
+    let mut indices = Box::new([0x0Fu16; SCREEN_PIXELS]);
+    for (i, index) in indices.iter_mut().enumerate() {
+        if i % 2 == 0 {
+            *index = 0x30;
+        }
+    }
+    let is_grey = |pixel: u32| channel_diff(pixel, pixel >> 8, 0) <= 8 && channel_diff(pixel, pixel >> 8, 8) <= 8;
+    NtscFilter::new(NtscFilterPreset::Composite).apply(&indices, 0, &palette, &mut output);
+    assert!(!is_grey(output[100 * NTSC_OUTPUT_WIDTH as usize + 256]));
+    NtscFilter::new(NtscFilterPreset::SVideo).apply(&indices, 0, &palette, &mut output);
+    assert!(is_grey(output[100 * NTSC_OUTPUT_WIDTH as usize + 256]));
+
+    // RGB is a plain palette lookup
+    NtscFilter::new(NtscFilterPreset::Rgb).apply(&indices, 0, &palette, &mut output);
+    let Color { r, g, b } = palette.color(0x30);
+    assert_eq!(output[0], (r as u32) << 16 | (g as u32) << 8 | (b as u32));
+}
+
+#[test]
+fn test_ntsc_filter_widths_clamped() {
+    use crate::ppu::generate_ntsc_palette;
+
+    let palette = generate_ntsc_palette(&NtscPaletteParams::default());
+    let mut output = Box::new([0u32; NTSC_OUTPUT_PIXELS]);
+    let indices = Box::new([0x16u16; SCREEN_PIXELS]);
+    let composite = NtscFilterPreset::Composite.settings().unwrap();
+
+    // Neither an empty window nor one wider than a line breaks the filter
+    for (luma_width, chroma_width) in [(0, 0), (SAMPLES_PER_LINE + 1, usize::MAX)] {
+        let mut filter = NtscFilter::with_settings(Some(NtscFilterSettings { luma_width, chroma_width, ..composite }));
+        let settings = filter.settings.unwrap();
+        assert!((1..=SAMPLES_PER_LINE).contains(&settings.luma_width));
+        assert!((1..=SAMPLES_PER_LINE).contains(&settings.chroma_width));
+        filter.apply(&indices, 0, &palette, &mut output);
+        assert_ne!(output[100 * NTSC_OUTPUT_WIDTH as usize + 256], 0);
+    }
+}
@@ -0,0 +1,147 @@
+use std::f32::consts::PI;
+use crate::ppu::{Color, Palette, PALETTE_COLORS};
+
+/// The composite signal levels for luma 0-3, for the low and high halves of the color wave.
+/// The levels are relative to a 1.0 V sync, from https://www.nesdev.org/wiki/NTSC_video#Brightness_Levels
+const SIGNAL_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+const SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+const SIGNAL_BLACK: f32 = SIGNAL_LOW[1];
+const SIGNAL_WHITE: f32 = SIGNAL_HIGH[3];
+/// The color emphasis bits attenuate the signal during part of each color cycle, by this much.
+/// https://www.nesdev.org/wiki/NTSC_video#Color_Tint_Bits
+pub(super) const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The PPU generates each color cycle in 12 steps of its master clock.
+pub(super) const PHASES: usize = 12;
+/// Where the decoder's I axis sits, in twelfths of a color cycle. Chosen so the hues match a TV
+/// with its hue knob centred.
+const DECODER_PHASE: f32 = 3.5;
+
+/// Controls for generating a palette, like the knobs on a TV.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NtscPaletteParams {
+    /// Rotates every hue, in degrees
+    pub hue: f32,
+    /// Multiplies the color, 0.0 is greyscale
+    pub saturation: f32,
+    /// Multiplies the whole signal
+    pub contrast: f32,
+    /// Added to the brightness, -1.0 to 1.0
+    pub brightness: f32,
+    /// The gamma of the emulated TV, which the colors are converted from to the sRGB gamma of 2.2
+    pub gamma: f32,
+}
+
+impl Default for NtscPaletteParams {
+    fn default() -> NtscPaletteParams {
+        NtscPaletteParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+/// Generates all 512 colors by simulating the PPU's composite signal for each color, then decoding
+/// it like a TV would: averaging the luma (Y) and demodulating the chroma (I and Q) over one color
+/// cycle, then converting YIQ to RGB.
+/// https://www.nesdev.org/wiki/NTSC_video#Emulating_in_C++_code
+pub fn generate_ntsc_palette(params: &NtscPaletteParams) -> Palette {
+    let mut colors = [Color::default(); PALETTE_COLORS];
+    for (index, color) in colors.iter_mut().enumerate() {
+        *color = generate_color(index, params);
+    }
+    Palette::from_colors(colors)
+}
+
+fn generate_color(index: usize, params: &NtscPaletteParams) -> Color {
+    let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+    for phase in 0..PHASES {
+        let level = signal_level(index, phase);
+        let angle = decoder_angle(phase, params);
+        y += level;
+        i += level * angle.cos();
+        q += level * angle.sin();
+    }
+    yiq_to_color(y / PHASES as f32, i / PHASES as f32, q / PHASES as f32, params)
+}
+
+/// The composite signal for a 9-bit palette index at one of the 12 phases of the color cycle,
+/// scaled so black is 0.0 and white is 1.0.
+pub(super) fn signal_level(index: usize, phase: usize) -> f32 {
+    let hue = index & 0x0F;
+    let luma = index >> 4 & 0b11;
+    let emphasis = index >> 6;
+
+    // Hue 0 is a grey at the high level, hue $D a grey at the low level, and $E-$F are black
+    let (low, high) = match hue {
+        0x00 => (SIGNAL_HIGH[luma], SIGNAL_HIGH[luma]),
+        0x0D => (SIGNAL_LOW[luma], SIGNAL_LOW[luma]),
+        0x0E | 0x0F => (SIGNAL_BLACK, SIGNAL_BLACK),
+        _ => (SIGNAL_LOW[luma], SIGNAL_HIGH[luma]),
+    };
+
+    // The square wave for hue N is high for 6 of the 12 phases, starting at a phase set by N
+    let in_color_phase = |color: usize| (color + phase) % PHASES < 6;
+
+    let mut signal = if in_color_phase(hue) { high } else { low };
+
+    // Red, green and blue emphasis each attenuate a third of the color cycle
+    let attenuated = (emphasis & 0b001 != 0 && in_color_phase(0x0C))
+        || (emphasis & 0b010 != 0 && in_color_phase(0x04))
+        || (emphasis & 0b100 != 0 && in_color_phase(0x08));
+    if attenuated && hue < 0x0E {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+/// The angle of the decoder's reference carrier at a phase of the color cycle.
+pub(super) fn decoder_angle(phase: usize, params: &NtscPaletteParams) -> f32 {
+    PI * (phase as f32 + DECODER_PHASE) / 6.0 + params.hue.to_radians()
+}
+
+/// Applies the TV's knobs to a decoded YIQ color, then converts it to RGB.
+pub(super) fn yiq_to_color(y: f32, i: f32, q: f32, params: &NtscPaletteParams) -> Color {
+    let y = y * params.contrast + params.brightness;
+    let i = i * params.saturation * params.contrast;
+    let q = q * params.saturation * params.contrast;
+
+    // The FCC's YIQ to RGB matrix
+    let r = y + 0.946882 * i + 0.623557 * q;
+    let g = y - 0.274788 * i - 0.635691 * q;
+    let b = y - 1.108545 * i + 1.709007 * q;
+
+    let to_srgb = |value: f32| -> u8 {
+        let corrected = value.max(0.0).powf(params.gamma / 2.2);
+        (corrected * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    Color { r: to_srgb(r), g: to_srgb(g), b: to_srgb(b) }
+}
+
+#[test]
+fn test_generate_ntsc_palette() {
+    let palette = generate_ntsc_palette(&NtscPaletteParams::default());
+
+    let black = palette.color(0x0F);
+    assert_eq!((black.r, black.g, black.b), (0, 0, 0));
+    let white = palette.color(0x30);
+    assert!(white.r > 0xF0 && white.g > 0xF0 && white.b > 0xF0);
+
+    let red = palette.color(0x16);
+    assert!(red.r > red.g && red.r > red.b);
+    let green = palette.color(0x1A);
+    assert!(green.g > green.r && green.g > green.b);
+    let blue = palette.color(0x12);
+    assert!(blue.b > blue.r && blue.b > blue.g);
+
+    // Emphasizing blue darkens the reds
+    let emphasized = palette.color(0b100 << 6 | 0x16);
+    assert!(emphasized.r < red.r);
+
+    let grey = generate_ntsc_palette(&NtscPaletteParams { saturation: 0.0, ..NtscPaletteParams::default() }).color(0x16);
+    assert!(grey.r == grey.g && grey.g == grey.b);
+}
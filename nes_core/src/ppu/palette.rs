@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::path::Path;
+use crate::ppu::Color;
+use crate::ppu::ntsc_palette::{EMPHASIS_ATTENUATION, generate_ntsc_palette, NtscPaletteParams};
+
+/// The number of colors the PPU can output: 64 palette entries, in 8 variations for the
+/// combinations of the PPUMASK color emphasis bits.
+pub const PALETTE_COLORS: usize = 512;
+
+/// Maps the 9-bit palette indices the PPU outputs to RGB colors.
+/// The NES doesn't output RGB, so there's no one true palette; emulators and capture devices
+/// each decode the composite video signal a bit differently.
+/// https://www.nesdev.org/wiki/PPU_palettes
+#[derive(Clone)]
+pub struct Palette {
+    colors: Box<[Color; PALETTE_COLORS]>,
+}
+
+/// The palettes built into the emulator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuiltinPalette {
+    /// Nestopia's RGB palette
+    NestopiaRgb,
+    /// A palette decoded from a composite NTSC signal
+    Ntsc,
+    /// Generated from a model of the composite signal, with the default parameters
+    GeneratedNtsc,
+}
+
+impl BuiltinPalette {
+    pub const ALL: [BuiltinPalette; 3] = [BuiltinPalette::NestopiaRgb, BuiltinPalette::Ntsc, BuiltinPalette::GeneratedNtsc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinPalette::NestopiaRgb => "Nestopia RGB",
+            BuiltinPalette::Ntsc => "NTSC",
+            BuiltinPalette::GeneratedNtsc => "NTSC (Generated)",
+        }
+    }
+
+    fn pal_data(self) -> Option<&'static [u8]> {
+        match self {
+            BuiltinPalette::NestopiaRgb => Some(include_bytes!("../../../nestopia_rgb.pal")),
+            BuiltinPalette::Ntsc => Some(include_bytes!("../../../ntscpalette_24bpp.pal")),
+            BuiltinPalette::GeneratedNtsc => None,
+        }
+    }
+}
+
+impl Palette {
+    pub fn builtin(palette: BuiltinPalette) -> Palette {
+        match palette.pal_data() {
+            Some(data) => Palette::from_pal_data(data).unwrap(),
+            None => generate_ntsc_palette(&NtscPaletteParams::default()),
+        }
+    }
+
+    pub fn from_colors(colors: [Color; PALETTE_COLORS]) -> Palette {
+        Palette { colors: Box::new(colors) }
+    }
+
+    /// Loads a .pal file, either 64 colors (192 bytes) or 512 colors with every combination of the
+    /// emphasis bits (1536 bytes). Colors are stored as 3 bytes, R, G then B.
+    pub fn load(path: &Path) -> Result<Palette, Box<dyn Error>> {
+        let data = std::fs::read(path)?;
+        Palette::from_pal_data(&data)
+    }
+
+    pub fn from_pal_data(data: &[u8]) -> Result<Palette, Box<dyn Error>> {
+        let read_colors = |colors: &mut [Color]| {
+            for (color, rgb) in colors.iter_mut().zip(data.chunks_exact(3)) {
+                *color = Color { r: rgb[0], g: rgb[1], b: rgb[2] };
+            }
+        };
+
+        match data.len() {
+            192 => {
+                let mut colors = [Color::default(); 64];
+                read_colors(&mut colors);
+                Ok(Palette::from_base_colors(&colors))
+            }
+            1536 => {
+                let mut colors = Box::new([Color::default(); PALETTE_COLORS]);
+                read_colors(&mut colors[..]);
+                Ok(Palette { colors })
+            }
+            len => Err(format!("A palette file should be 192 or 1536 bytes long, this is {len} bytes").into()),
+        }
+    }
+
+    /// Makes a 512 color palette from the 64 base colors, approximating the emphasis bits by
+    /// dimming the non-emphasized color channels.
+    /// https://www.nesdev.org/wiki/NTSC_video#Color_Tint_Bits
+    pub fn from_base_colors(base_colors: &[Color; 64]) -> Palette {
+        let mut colors = Box::new([Color::default(); PALETTE_COLORS]);
+        for (i, color) in colors.iter_mut().enumerate() {
+            let base = base_colors[i & 0x3F];
+            let emphasis = i >> 6;
+            // Columns $xE and $xF are black, and the emphasis bits don't change them
+            if emphasis == 0 || i & 0x0E == 0x0E {
+                *color = base;
+                continue;
+            }
+
+            let attenuate = |value: u8, emphasized: bool| -> u8 {
+                if emphasized {
+                    value
+                } else {
+                    // Scaling the RGB value only approximates attenuating the signal, but it's close enough
+                    (value as f32 * EMPHASIS_ATTENUATION).round() as u8
+                }
+            };
+            let (red, green, blue) = (emphasis & 0b001 != 0, emphasis & 0b010 != 0, emphasis & 0b100 != 0);
+            // Each channel is dimmed by the emphasis of either of the other two
+            *color = Color {
+                r: attenuate(base.r, !(green || blue)),
+                g: attenuate(base.g, !(red || blue)),
+                b: attenuate(base.b, !(red || green)),
+            };
+        }
+        Palette { colors }
+    }
+
+    /// The color for a 9-bit palette index from the display buffer.
+    pub fn color(&self, index: u16) -> Color {
+        self.colors[index as usize % PALETTE_COLORS]
+    }
+
+    pub fn colors(&self) -> &[Color; PALETTE_COLORS] {
+        &self.colors
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::builtin(BuiltinPalette::NestopiaRgb)
+    }
+}
+
+#[test]
+fn test_emphasis_palette() {
+    let palette = Palette::default();
+    let white = palette.color(0x30);
+
+    // Emphasizing red dims green and blue
+    let red = palette.color(0b001 << 6 | 0x30);
+    assert_eq!(red.r, white.r);
+    assert!(red.g < white.g && red.b < white.b);
+
+    // Emphasizing all three dims everything, except black
+    let all = palette.color(0b111 << 6 | 0x30);
+    assert!(all.r < white.r && all.g < white.g && all.b < white.b);
+    assert_eq!(palette.color(0b111 << 6 | 0x0F).r, palette.color(0x0F).r);
+}
+
+#[test]
+fn test_from_pal_data() {
+    let mut data = vec![0u8; 1536];
+    data[3 * 0x1C1..3 * 0x1C1 + 3].copy_from_slice(&[1, 2, 3]);
+    let palette = Palette::from_pal_data(&data).unwrap();
+    let Color { r, g, b } = palette.color(0x1C1);
+    assert_eq!((r, g, b), (1, 2, 3));
+
+    assert!(Palette::from_pal_data(&data[..1000]).is_err());
+}
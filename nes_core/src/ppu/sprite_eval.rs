@@ -0,0 +1,352 @@
+use super::*;
+
+/// The sprite evaluation state for the line being rendered, spread over dots 1-256 like the real PPU.
+/// The sprites found are fetched into `cur_line_sprites` during dots 257-320, ready for the next line.
+/// https://www.nesdev.org/wiki/PPU_sprite_evaluation
+pub(super) struct SpriteEvaluation {
+    secondary_oam: [u8; 32],
+    /// The next byte of secondary OAM to be written
+    secondary_index: usize,
+    state: SpriteEvalState,
+    /// The OAM byte read on the previous (odd) dot, written to secondary OAM on the next (even) dot
+    read_buffer: u8,
+    /// Whether the first sprite in secondary OAM is sprite 0
+    sprite_0_found: bool,
+    /// The low pattern byte of the sprite being fetched
+    fetched_pattern_lo: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SpriteEvalState {
+    /// Check if the Y coordinate at OAM[n][0] is on this line
+    CheckY,
+    /// Copying the rest of an in-range sprite to secondary OAM
+    CopySprite { bytes_left: u8 },
+    /// Secondary OAM is full, search for a 9th sprite to set the overflow flag
+    CheckOverflow,
+    /// Found the 9th sprite, read through the rest of it
+    ReadOverflowSprite { bytes_left: u8 },
+    /// All 64 sprites were checked, OAM is read but nothing more is copied
+    Done,
+}
+
+impl SpriteEvaluation {
+    pub(super) fn new() -> SpriteEvaluation {
+        SpriteEvaluation {
+            secondary_oam: [0xFF; 32],
+            secondary_index: 0,
+            state: SpriteEvalState::CheckY,
+            read_buffer: 0xFF,
+            sprite_0_found: false,
+            fetched_pattern_lo: 0,
+        }
+    }
+
+    fn num_sprites_found(&self) -> usize {
+        self.secondary_index / 4
+    }
+}
+
+/// Called on dots 1-256 of the visible scanlines when rendering is enabled.
+pub(super) fn step_sprite_evaluation(ppu: &mut PPU) {
+    let dot = ppu.dot;
+    match dot {
+        // Secondary OAM is cleared to $FF, one byte every two dots. OAMDATA reads $FF during this.
+        1..=64 => {
+            ppu.oam_data_bus = 0xFF;
+            if dot & 1 == 0 {
+                ppu.sprite_eval.secondary_oam[(dot / 2 - 1) as usize] = 0xFF;
+            }
+            if dot == 64 {
+                ppu.sprite_eval.secondary_index = 0;
+                ppu.sprite_eval.state = SpriteEvalState::CheckY;
+                ppu.sprite_eval.sprite_0_found = false;
+            }
+        }
+        // Odd dots read from OAM, even dots write to secondary OAM.
+        65..=256 if dot & 1 == 1 => {
+            let value = ppu.oam[ppu.oam_addr as usize];
+            ppu.sprite_eval.read_buffer = value;
+            ppu.oam_data_bus = value;
+        }
+        65..=256 => {
+            sprite_evaluation_write(ppu);
+        }
+        _ => {}
+    }
+}
+
+/// "n" is the sprite index in OAM, "m" the byte within the sprite. The PPU keeps both in OAMADDR.
+fn sprite_evaluation_write(ppu: &mut PPU) {
+    use self::SpriteEvalState::*;
+
+    let value = ppu.sprite_eval.read_buffer;
+    // A Y coordinate is compared against the current line, so sprites appear one line lower than their Y.
+    let in_range = ppu.scanline.wrapping_sub(value as u32) < ppu.control.sprite_size.height();
+    let oam_addr = ppu.oam_addr;
+    let is_first_check = ppu.dot == 66;
+    let eval = &mut ppu.sprite_eval;
+
+    match eval.state {
+        CheckY => {
+            // The Y coordinate is always copied, but only kept if the sprite's in range.
+            eval.secondary_oam[eval.secondary_index] = value;
+            if in_range {
+                if is_first_check {
+                    eval.sprite_0_found = true;
+                }
+                eval.secondary_index += 1;
+                eval.state = CopySprite { bytes_left: 3 };
+                ppu.oam_addr = oam_addr.wrapping_add(1);
+            } else {
+                let (next_addr, n_overflowed) = oam_addr.overflowing_add(4);
+                ppu.oam_addr = next_addr;
+                if n_overflowed {
+                    eval.state = Done;
+                }
+            }
+        }
+        CopySprite { bytes_left } => {
+            eval.secondary_oam[eval.secondary_index] = value;
+            eval.secondary_index += 1;
+            let (next_addr, n_overflowed) = oam_addr.overflowing_add(1);
+            ppu.oam_addr = next_addr;
+            eval.state = if n_overflowed {
+                Done
+            } else if bytes_left > 1 {
+                CopySprite { bytes_left: bytes_left - 1 }
+            } else if eval.secondary_index >= eval.secondary_oam.len() {
+                CheckOverflow
+            } else {
+                CheckY
+            };
+        }
+        CheckOverflow => {
+            // Secondary OAM is full, so this write is a read instead.
+            ppu.oam_data_bus = eval.secondary_oam[0];
+            if in_range {
+                ppu.sprite_overflow = true;
+                let (next_addr, n_overflowed) = oam_addr.overflowing_add(1);
+                ppu.oam_addr = next_addr;
+                eval.state = if n_overflowed { Done } else { ReadOverflowSprite { bytes_left: 3 } };
+            } else {
+                // The hardware bug: both n and m are incremented, without a carry from m into n.
+                // This makes the search walk diagonally through OAM, treating tile numbers,
+                // attributes and X coordinates as Y coordinates.
+                let next_n = (oam_addr & 0xFC) as u32 + 4;
+                let next_m = oam_addr.wrapping_add(1) & 0b11;
+                ppu.oam_addr = next_n as u8 | next_m;
+                if next_n > 0xFF {
+                    eval.state = Done;
+                }
+            }
+        }
+        ReadOverflowSprite { bytes_left } => {
+            ppu.oam_data_bus = eval.secondary_oam[0];
+            let (next_addr, n_overflowed) = oam_addr.overflowing_add(1);
+            ppu.oam_addr = next_addr;
+            eval.state = if n_overflowed || bytes_left <= 1 { Done } else { ReadOverflowSprite { bytes_left: bytes_left - 1 } };
+        }
+        Done => {
+            // Attempt (and fail) to copy OAM[n][0] to secondary OAM, then move on to the next sprite.
+            ppu.oam_data_bus = eval.secondary_oam[0];
+            ppu.oam_addr = oam_addr.wrapping_add(4);
+        }
+    }
+}
+
+/// Called on dots 257-320 when rendering is enabled. Each of the 8 sprite slots takes 8 dots: two
+/// garbage nametable fetches, then the low and high pattern bytes. Slots without a sprite still
+/// fetch the pattern for tile $FF, which matters to mappers watching the PPU address bus.
+pub(super) fn step_sprite_fetches(ppu: &mut PPU) {
+    let slot = ((ppu.dot - 257) / 8) as usize;
+    let cycle = (ppu.dot - 257) % 8;
+
+    if cycle == 0 && slot == 0 {
+        // The pre-render line still makes the fetches, from whatever was left in secondary OAM,
+        // but sprites are never drawn on line 0, so every slot is left empty.
+        let num_sprites = if ppu.scanline == LAST_SCANLINE { 0 } else { ppu.sprite_eval.num_sprites_found() };
+        ppu.cur_line_num_sprites = num_sprites;
+    }
+
+    let sprite_data: [u8; 4] = ppu.sprite_eval.secondary_oam[slot * 4..slot * 4 + 4].try_into().unwrap();
+    // OAMDATA reads see the secondary OAM bytes as they're read: Y, tile, attributes, then X for the rest.
+    ppu.oam_data_bus = sprite_data[(cycle as usize).min(SPRITE_X)];
+
+    match cycle {
+        1 | 3 => {
+            // Garbage nametable fetches
+            ppu.mapper.read_nametable(0x2000 | (ppu.v_addr & 0x0FFF));
+        }
+        5 => {
+            let pattern_addr = sprite_pattern_addr(ppu, &sprite_data);
+            ppu.sprite_eval.fetched_pattern_lo = ppu.mapper.read_pattern_table(pattern_addr);
+        }
+        7 => {
+            let pattern_addr = sprite_pattern_addr(ppu, &sprite_data);
+            let mut pat_upper = ppu.mapper.read_pattern_table(pattern_addr + 8);
+            let mut pat_lower = ppu.sprite_eval.fetched_pattern_lo;
+
+            if slot >= ppu.cur_line_num_sprites {
+                ppu.cur_line_sprites[slot] = SpriteRowSlice::hidden();
+                return;
+            }
+
+            let attrs = sprite_data[SPRITE_ATTRIBUTES];
+            if attrs & SPRITE_ATTR_FLIP_H == 0 {
+                pat_lower = pat_lower.reverse_bits();
+                pat_upper = pat_upper.reverse_bits();
+            }
+            let pattern2 = interleave_bits(pat_lower, pat_upper);
+
+            let start_x: u8 = sprite_data[SPRITE_X];
+            ppu.cur_line_sprites[slot] = SpriteRowSlice {
+                start_x,
+                end_x: start_x as u16 + 8,
+                pattern2,
+                above_bg: (attrs & SPRITE_ATTR_BEHIND_BG) == 0,
+                palette_base_addr: 0x10 | ((attrs & SPRITE_ATTR_PALETTE) << 2),
+                is_sprite_0: slot == 0 && ppu.sprite_eval.sprite_0_found,
+            };
+        }
+        _ => {}
+    }
+}
+
+/// The last dots of the line and the idle dot 0 read the first byte of secondary OAM.
+pub(super) fn step_sprite_idle(ppu: &mut PPU) {
+    ppu.oam_data_bus = ppu.sprite_eval.secondary_oam[0];
+}
+
+/// The address of the low pattern byte of the sprite's row on the current line.
+fn sprite_pattern_addr(ppu: &PPU, sprite_data: &[u8; 4]) -> u16 {
+    let y = sprite_data[SPRITE_Y] as u32;
+    let attrs = sprite_data[SPRITE_ATTRIBUTES];
+    let tile_index = sprite_data[SPRITE_TILE_INDEX];
+    let line = ppu.scanline;
+
+    // Empty slots ($FF) are out of range, so mask the offset like the hardware's row counter.
+    match ppu.control.sprite_size {
+        SpriteSize::Size8x8 => {
+            let mut y_offset = line.wrapping_sub(y) & 7;
+            if attrs & SPRITE_ATTR_FLIP_V != 0 {
+                y_offset = 7 - y_offset;
+            }
+            ppu.control.sprite_pattern_table + (tile_index as u16) * 16 + (y_offset as u16)
+        }
+        SpriteSize::Size8x16 => {
+            let mut y_offset = line.wrapping_sub(y) & 15;
+            let pattern_table = if tile_index & 1 == 1 { 0x1000 } else { 0x0000 };
+            let mut tile_index = tile_index & !1;
+            if attrs & SPRITE_ATTR_FLIP_V != 0 {
+                if y_offset >= 8 {
+                    y_offset -= 8;
+                } else {
+                    tile_index += 1;
+                }
+                y_offset = 7 - y_offset;
+            } else if y_offset >= 8 {
+                tile_index += 1;
+                y_offset -= 8;
+            }
+            pattern_table + (tile_index as u16) * 16 + (y_offset as u16)
+        }
+    }
+}
+
+#[cfg(test)]
+fn run_line(ppu: &mut PPU, line: u32) {
+    ppu.scanline = line;
+    ppu.dot = 0;
+    while ppu.scanline == line {
+        ppu.step_cycle();
+    }
+}
+
+#[test]
+fn test_sprite_overflow() {
+    let mut ppu = new_test_ppu();
+    // Move every sprite off screen
+    ppu.oam.fill(0xF0);
+
+    // 8 sprites on line 20 don't overflow
+    for i in 0..8 {
+        ppu.oam[i * 4 + SPRITE_Y] = 20;
+        ppu.oam[i * 4 + SPRITE_X] = i as u8 * 10;
+    }
+    run_line(&mut ppu, 20);
+    assert!(!ppu.sprite_overflow);
+    assert_eq!(ppu.cur_line_num_sprites, 8);
+    assert!(ppu.cur_line_sprites[0].is_sprite_0);
+    assert_eq!(ppu.cur_line_sprites[7].start_x, 70);
+
+    // A 9th sprite sets the overflow flag
+    ppu.oam[20 * 4 + SPRITE_Y] = 20;
+    run_line(&mut ppu, 20);
+    assert!(ppu.sprite_overflow);
+    assert_eq!(ppu.cur_line_num_sprites, 8);
+    assert_ne!(ppu.read_register(PPUSTATUS) & 0b0010_0000, 0);
+}
+
+#[test]
+fn test_sprite_overflow_diagonal_bug() {
+    let mut ppu = new_test_ppu();
+    ppu.oam.fill(0xF0);
+
+    for i in 0..8 {
+        ppu.oam[i * 4 + SPRITE_Y] = 20;
+    }
+    // After sprite 8 is out of range, the search moves on to byte 1 (the tile index) of sprite 9,
+    // so a 9th sprite's Y coordinate is missed...
+    ppu.oam[9 * 4 + SPRITE_Y] = 20;
+    run_line(&mut ppu, 20);
+    assert!(!ppu.sprite_overflow);
+
+    // ...but a tile index that looks like an in-range Y coordinate triggers a false overflow.
+    ppu.oam[9 * 4 + SPRITE_TILE_INDEX] = 18;
+    run_line(&mut ppu, 20);
+    assert!(ppu.sprite_overflow);
+}
+
+#[test]
+fn test_oamdata_reads_during_rendering() {
+    let mut ppu = new_test_ppu();
+    ppu.oam.fill(0xF0);
+    ppu.oam[0] = 20;
+    ppu.oam[1] = 0x42;
+
+    ppu.scanline = 20;
+    ppu.dot = 0;
+    // Secondary OAM is being cleared
+    while ppu.dot < 30 {
+        ppu.step_cycle();
+    }
+    assert_eq!(ppu.read_register(OAMDATA), 0xFF);
+
+    // Fetching sprite 0's tile index from secondary OAM
+    while ppu.dot < 259 {
+        ppu.step_cycle();
+    }
+    assert_eq!(ppu.read_register(OAMDATA), 0x42);
+}
+
+#[test]
+fn test_no_sprites_on_line_0() {
+    let mut ppu = new_test_ppu();
+    ppu.oam.fill(0xF0);
+    for i in 0..8 {
+        ppu.oam[i * 4 + SPRITE_Y] = 239;
+        ppu.oam[i * 4 + SPRITE_X] = i as u8 * 10;
+    }
+    run_line(&mut ppu, 239);
+    assert_eq!(ppu.cur_line_num_sprites, 8);
+
+    // Secondary OAM still has line 239's sprites, but they aren't loaded for line 0
+    run_line(&mut ppu, LAST_SCANLINE);
+    assert_eq!(ppu.cur_line_num_sprites, 0);
+    for sprite in ppu.cur_line_sprites.iter() {
+        assert_eq!(sprite.start_x, 0xFF);
+        assert_eq!(sprite.pattern2, 0);
+        assert!(!sprite.is_sprite_0);
+    }
+}
@@ -4,13 +4,9 @@ use crate::cpu_ops::*;
 use crate::nes::{ NES, StatusRegister };
 
 fn new_nes() -> NES {
-    let cart: Cartridge = Cartridge {
-        prg_rom: vec![0; 0x4000],
-        chr: CHR::ROM(vec![0; 0x2000].into_boxed_slice()),
-        mapper_descriptor: MapperDescriptor::NROM,
+    let cart = Cartridge {
         mirroring: crate::cartridge::NametableMirroring::Horizontal,
-        prg_ram_size: 0,
-        prg_ram_battery_backed: false,
+        ..Cartridge::test_cart(MapperDescriptor::NROM, vec![0; 0x4000], CHR::ROM(vec![0; 0x2000].into_boxed_slice()))
     };
 
     let mut nes = NES::from_cart(cart);